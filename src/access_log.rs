@@ -0,0 +1,422 @@
+// A record of every request the server answers, one line per request.
+//
+// Formatting and writing the lines happens on a thread of its own. Workers hand their entries
+// over through a channel, which never blocks, so a slow disk or terminal never holds up a
+// response.
+
+use crate::http::{iso_8601, utc, Method, Request, StatusCode, Version};
+use crate::json::json_string;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// How many rotated log files are kept, as `access.log.1` (the newest) to `access.log.5`.
+pub const KEEP_ROTATED: usize = 5;
+
+/// How access log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The NCSA Common Log Format.
+    Common,
+    /// The Common Log Format, plus the referrer and user agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {:?}, expected common, combined or json",
+                s
+            )),
+        }
+    }
+}
+
+/// The parts of a request that make it into the log. They're copied out before the request is
+/// handed to its handler, which takes ownership of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestInfo {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl From<&Request> for RequestInfo {
+    fn from(request: &Request) -> Self {
+        RequestInfo {
+            method: request.method.clone(),
+            target: request.target.clone(),
+            version: request.version,
+            referer: request.headers.get("Referer").map(str::to_string),
+            user_agent: request.headers.get("User-Agent").map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub client: SocketAddr,
+    /// When the request arrived.
+    pub time: SystemTime,
+    /// None if the request was so broken we couldn't make sense of it.
+    pub request: Option<RequestInfo>,
+    pub status: StatusCode,
+    /// Bytes of body sent, not counting the headers.
+    pub bytes: u64,
+    /// How long it took to handle the request and send the response.
+    pub latency: Duration,
+}
+
+impl Entry {
+    /// Formats the entry as a line, without its line ending.
+    ///
+    /// Neither of the NCSA formats has a place for latency, so like Apache's `%D`, it goes on the
+    /// end of the line, in microseconds. Log parsers generally ignore anything extra at the end.
+    pub fn format(&self, format: LogFormat) -> String {
+        let mut line = String::new();
+
+        if format == LogFormat::Json {
+            let request = self.request.as_ref();
+            let field = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+            let _ = write!(
+                line,
+                "{{\"time\":{},\"client\":{},\"method\":{},\"target\":{},\"version\":{},\
+                 \"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"latency_us\":{}}}",
+                json_string(&iso_8601(self.time)),
+                json_string(&self.client.ip().to_string()),
+                field(request.map(|r| r.method.as_str())),
+                field(request.map(|r| r.target.as_str())),
+                field(request.map(|r| r.version.as_str())),
+                self.status.as_u16(),
+                self.bytes,
+                field(request.and_then(|r| r.referer.as_deref())),
+                field(request.and_then(|r| r.user_agent.as_deref())),
+                self.latency.as_micros()
+            );
+            return line;
+        }
+
+        // 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /apache_pb.gif HTTP/1.0" 200 2326
+        //
+        // The dashes are for the client's identity and user name, which we never know. A byte
+        // count of zero is written as a dash too.
+
+        let request_line = match &self.request {
+            Some(r) => format!("{} {} {}", r.method, r.target, r.version),
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let _ = write!(
+            line,
+            "{} - - [{}] {} {} {}",
+            self.client.ip(),
+            clf_date(self.time),
+            quoted(&request_line),
+            self.status.as_u16(),
+            bytes
+        );
+
+        if format == LogFormat::Combined {
+            let request = self.request.as_ref();
+            let _ = write!(
+                line,
+                " {} {}",
+                quoted(request.and_then(|r| r.referer.as_deref()).unwrap_or("-")),
+                quoted(request.and_then(|r| r.user_agent.as_deref()).unwrap_or("-"))
+            );
+        }
+
+        let _ = write!(line, " {}", self.latency.as_micros());
+        line
+    }
+}
+
+// Quotes a field of a CLF line. Anything the client sent could contain quotes or line breaks,
+// which would let it forge log lines, so those are escaped the way Apache does it.
+
+fn quoted(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\x{:02x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats a time the way the Common Log Format wants it, e.g. `10/Oct/2000:13:55:36 +0000`.
+fn clf_date(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hours, minutes, seconds) = utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hours,
+        minutes,
+        seconds
+    )
+}
+
+/// Where log lines end up.
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// A log file that is moved aside once it grows past `max_size` bytes, so that it can't fill the
+/// disk. `access.log` becomes `access.log.1`, `access.log.1` becomes `access.log.2`, and so on,
+/// with the oldest falling off the end.
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    /// Zero means the file is never rotated.
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            size,
+            max_size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        for n in (1..KEEP_ROTATED).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+
+        *self = RotatingFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+/// Sends entries off to be written by the logging thread. Dropping the log waits for everything
+/// that has been logged so far to be written.
+pub struct AccessLog {
+    sender: Option<Sender<Entry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Logs nothing at all.
+    pub fn disabled() -> AccessLog {
+        AccessLog {
+            sender: None,
+            writer: None,
+        }
+    }
+
+    /// Logs to standard output.
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog::start(Sink::Stdout, format)
+    }
+
+    /// Logs to a file, which is rotated whenever it would grow past `max_size` bytes. A
+    /// `max_size` of zero turns rotation off.
+    pub fn file<P: AsRef<Path>>(
+        path: P,
+        max_size: u64,
+        format: LogFormat,
+    ) -> io::Result<AccessLog> {
+        let file = RotatingFile::open(path.as_ref(), max_size)?;
+        Ok(AccessLog::start(Sink::File(file), format))
+    }
+
+    fn start(sink: Sink, format: LogFormat) -> AccessLog {
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || write_entries(receiver, sink, format));
+
+        AccessLog {
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+
+    /// Queues an entry to be written. This never blocks.
+    pub fn log(&self, entry: Entry) {
+        if let Some(sender) = &self.sender {
+            // The writer only goes away if writing panicked, and a broken log shouldn't take the
+            // server down with it.
+            let _ = sender.send(entry);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Closing the channel is what tells the writer to finish up.
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_entries(receiver: Receiver<Entry>, mut sink: Sink, format: LogFormat) {
+    let stdout = io::stdout();
+
+    // Wait for an entry, then write it along with any others that have piled up in the meantime,
+    // and flush them all in one go.
+
+    while let Ok(entry) = receiver.recv() {
+        let result = match &mut sink {
+            Sink::Stdout => {
+                let mut out = stdout.lock();
+                let mut result = writeln!(out, "{}", entry.format(format));
+                while let (Ok(()), Ok(entry)) = (&result, receiver.try_recv()) {
+                    result = writeln!(out, "{}", entry.format(format));
+                }
+                result.and_then(|_| out.flush())
+            }
+            Sink::File(file) => {
+                let mut result = file.write_line(&entry.format(format));
+                while let (Ok(()), Ok(entry)) = (&result, receiver.try_recv()) {
+                    result = file.write_line(&entry.format(format));
+                }
+                result.and_then(|_| file.file.flush())
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("unable to write to the access log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> Entry {
+        Entry {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            // 10 Oct 2000 13:55:36 UTC
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request: Some(RequestInfo {
+                method: Method::Get,
+                target: "/apache_pb.gif".to_string(),
+                version: Version::Http10,
+                referer: Some("http://www.example.com/start.html".to_string()),
+                user_agent: Some("Mozilla/4.08 \"quoted\"".to_string()),
+            }),
+            status: StatusCode::Ok,
+            bytes: 2326,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_entries() {
+        let entry = entry();
+
+        assert_eq!(
+            entry.format(LogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" \
+             200 2326 1500"
+        );
+        assert_eq!(
+            entry.format(LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\" 1500"
+        );
+        assert_eq!(
+            entry.format(LogFormat::Json),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\
+             \"target\":\"/apache_pb.gif\",\"version\":\"HTTP/1.0\",\"status\":200,\"bytes\":2326,\
+             \"referer\":\"http://www.example.com/start.html\",\
+             \"user_agent\":\"Mozilla/4.08 \\\"quoted\\\"\",\"latency_us\":1500}"
+        );
+
+        let bad_request = Entry {
+            request: None,
+            status: StatusCode::BadRequest,
+            bytes: 0,
+            ..entry
+        };
+        assert_eq!(
+            bad_request.format(LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \"-\" \"-\" 1500"
+        );
+    }
+
+    #[test]
+    fn rotates_files_that_grow_too_large() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+
+        // Each line is under 100 bytes, so two of them fit in each file, but not three.
+        let log = AccessLog::file(&path, 250, LogFormat::Common).unwrap();
+        for _ in 0..(KEEP_ROTATED + 2) * 2 {
+            log.log(entry());
+        }
+        drop(log);
+
+        let lines = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(path.clone()), 2);
+        for n in 1..=KEEP_ROTATED {
+            assert_eq!(lines(dir.join(format!("access.log.{}", n))), 2, "{}", n);
+        }
+        assert!(!dir
+            .join(format!("access.log.{}", KEEP_ROTATED + 1))
+            .exists());
+    }
+}
@@ -1,3 +1,5 @@
+// These modules are only referenced by the commented-out exercises below.
+#[allow(unused_imports)]
 use rust_lang_book::{advanced_traits, fearless_concurrency, smart_pointers, state_pattern_blog};
 use std::boxed;
 use std::error;
@@ -10,7 +12,6 @@ type Result<T, E = Error> = result::Result<T, E>;
 /// programs that we've written. It's probably better to re-write these small programs as unit
 /// tests, but for now, we're treating them as small standalone programs that we can run and "see
 /// what happens".
-
 fn main() -> Result<()> {
     // state_pattern_blog::blog::Post::new();
 
@@ -1,119 +1,156 @@
-use rust_lang_book::thread_pool::ThreadPool;
+use rust_lang_book::access_log::AccessLog;
+use rust_lang_book::cache::CacheControl;
+use rust_lang_book::compression::Compression;
+use rust_lang_book::config::{Config, Mode, USAGE};
+use rust_lang_book::error::{fallible, ErrorPages, ServerError};
+use rust_lang_book::http::{Response, StatusCode};
+use rust_lang_book::router::Router;
+use rust_lang_book::server::HttpServer;
+#[cfg(unix)]
+use rust_lang_book::signal::{Signals, SIGINT, SIGTERM};
+use rust_lang_book::static_files::StaticFiles;
+use std::env;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::{thread, time};
+use std::path::Path;
+use std::process;
+use std::thread;
 
 /// Building a Multi-Threaded Web Server. Final project for the Rust Lang book:
 /// https://doc.rust-lang.org/book/ch20-00-final-project-a-web-server.html
-
 fn main() {
-    // bind to our localhost, at port 7878 (which is "rust" when typed into a phone)
-
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    // Everything about the server can be set on the command line, or in a config file. See
+    // `server --help`, or the config module, for how.
+
+    let (config, mode) = match Config::from_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    match mode {
+        Mode::Help => {
+            print!("{}", USAGE);
+            return;
+        }
+        Mode::CheckConfig => {
+            println!("The configuration is valid.");
+            return;
+        }
+        Mode::Serve => {}
+    }
 
-    // Create a thread pool that executes connections asynchronously. There are never more than four
-    // threads created, so our system won’t get overloaded if the server receives a lot of requests.
-    // If we make a request to /sleep, the server will be able to serve other requests by having
-    // another thread run them.
+    // The access log is written by a thread of its own, so workers never wait on it.
+
+    let access_log = match &config.access_log {
+        Some(path) => match AccessLog::file(path, config.access_log_max_size, config.log_format) {
+            Ok(access_log) => access_log,
+            Err(err) => {
+                eprintln!("error: unable to open {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        },
+        None => AccessLog::stdout(config.log_format),
+    };
 
-    // Using a thread pool is just one of many ways to improve the throughput of a web server.
-    // Other options are the fork/join model and the single-threaded async I/O model.
+    // Our pages, and anything else under /static/, are served from the document root. Static
+    // files also tell browsers how long they can keep them for, and text of any kind is
+    // compressed for the clients that can take it.
+
+    // Errors of any kind, from a missing page to a malformed request, are answered with the
+    // page for their status, such as 404.html, from the error pages directory.
+
+    let error_pages =
+        ErrorPages::new().directory(config.error_pages.as_ref().unwrap_or(&config.document_root));
+
+    let router = {
+        let root = &config.document_root;
+        let (hello, sleep) = (root.clone(), root.clone());
+        let delay = config.sleep;
+        Router::new()
+            .get("/", fallible(move |_| page(&hello, "hello.html")))
+            .get(
+                "/sleep",
+                fallible(move |_| {
+                    thread::sleep(delay);
+                    page(&sleep, "hello.html")
+                }),
+            )
+            .get(
+                "/static/*path",
+                CacheControl::new(
+                    &config.static_cache_control,
+                    StaticFiles::new(root).listings(config.directory_listings),
+                ),
+            )
+            .fallback(|_| Response::new(StatusCode::NotFound))
+            .wrap(Compression::new())
+            .wrap(error_pages.clone())
+    };
 
-    let pool = ThreadPool::new(4);
+    // Ctrl-C (SIGINT) or a SIGTERM from e.g. a service manager asks us to shut down gracefully:
+    // stop accepting connections, let the ones we have finish what they're doing, and then go.
+    // The signals are caught before the server starts, so one that arrives while it's starting
+    // still gets a graceful shutdown, rather than killing us on the spot.
+
+    let signals = catch_signals();
+
+    // bind to our localhost, at port 7878 (which is "rust" when typed into a phone), unless we've
+    // been told to listen somewhere else. The server then handles connections in the background
+    // until we shut it down.
+
+    let server = HttpServer::bind(&config.bind[..])
+        .config(config)
+        .router(router)
+        .error_pages(error_pages)
+        .access_log(access_log)
+        .spawn();
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
 
-    // To simulate the server shutting down gracefully, we can call `incoming().take(2)` to make it
-    // shutdown after 2 requests.
+    wait_for_signal(signals);
+    server.shutdown();
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+/// Starts catching SIGINT and SIGTERM.
+#[cfg(unix)]
+fn catch_signals() -> Signals {
+    Signals::install(&[SIGINT, SIGTERM]).expect("unable to catch signals")
+}
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+/// Waits for SIGINT or SIGTERM.
+#[cfg(unix)]
+fn wait_for_signal(signals: Signals) {
+    if let Ok(signum) = signals.wait() {
+        println!("Received signal {}.", signum);
     }
 }
 
-/// Handles a HTTP request, and returns a response. Both the request and response are read and
-/// written from/to the TCP streeam.
-///
-/// HTTP is a text-based protocol, and a request takes this format:
-///
-/// Method Request-URI HTTP-Version CRLF
-/// headers CRLF
-/// message-body
-///
-/// Responses have the following format:
-///
-/// HTTP-Version Status-Code Reason-Phrase CRLF
-/// headers CRLF
-/// message-body
-
-fn handle_connection(mut stream: TcpStream) {
-    // We are using 1024 here, because something shorter like 256 wouldn't be able to read the
-    // entire request made from a browser, given the extra headers. We are not supporting requests
-    // longer than 1024 bytes.
-
-    let mut buffer = [0; 1024];
-
-    // Read the tcp stream. If accessed from a browser or curl, this should set the following value
-    // into our buf:
-
-    // GET / HTTP/1.1
-    // Host: localhost:7878
-    // User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:78.0) Gecko/20100101 Firefox/78.0
-    // Accept: text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8
-    // Accept-Language: en-US,en;q=0.5
-    // Accept-Encoding: gzip, deflate
-    // Connection: keep-alive
-    // Upgrade-Insecure-Requests: 1
-
-    // or with `curl http://localhost:7878/`
-    // GET / HTTP/1.1
-    // Host: localhost:7878
-    // User-Agent: curl/7.64.1
-    // Accept: */*
-
-    let _bytes_read = stream
-        // WARNING: If the request contains more than buf.len() bytes, then we won't end up reading
-        // more than buf.len() bytes from the request, so the client will never get confirmation
-        // that the request was read. So once `stream` is dropped, the connection will be forcefully
-        // closed, resulting in a "connection reset" error in the client.
-        .read(&mut buffer)
-        .expect("unable to read request into buffer");
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(time::Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
-    };
-
-    let contents = fs::read_to_string(format!("{}", filename)).unwrap();
-
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+/// Without signals to catch, we run until we're killed.
+#[cfg(not(unix))]
+fn catch_signals() {}
 
-    // The write method on stream takes a &[u8] and sends those bytes directly down the connection.
-
-    let _bytes_written = stream
-        .write(response.as_bytes())
-        .expect("unable to write the response to the buffer.");
-
-    // Finally, flush will wait and prevent the program from continuing until all the bytes are
-    // written to the connection; TcpStream contains an internal buffer to minimize calls to the
-    // underlying operating system.
+#[cfg(not(unix))]
+fn wait_for_signal(_: ()) {
+    loop {
+        thread::park();
+    }
+}
 
-    stream
-        .flush()
-        .expect("unable to write all bytes from the internal buffer to the connection.");
+/// Responds with one of our HTML pages. The pages are part of the server rather than something a
+/// client asked for by name, so one that's missing is our fault, and a 500.
+fn page(root: &Path, filename: &str) -> Result<Response, ServerError> {
+    let path = root.join(filename);
+    let contents = fs::read_to_string(&path).map_err(|err| {
+        ServerError::internal(format!("unable to read {}: {}", path.display(), err))
+    })?;
+
+    Ok(Response::new(StatusCode::Ok)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(contents))
 }
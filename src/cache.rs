@@ -0,0 +1,235 @@
+// Caching: validators that let clients check whether their copy of a file is still current, and
+// Cache-Control, which tells them how long they can use it without checking.
+//
+// A response for a file carries an ETag (an opaque tag that changes whenever the file does) and a
+// Last-Modified date. When the client wants the file again, it sends them back:
+//
+// If-None-Match: "5f8c1e2a-9f"
+// If-Modified-Since: Sun, 18 Oct 2020 12:34:56 GMT
+//
+// and if the file hasn't changed, we answer 304 Not Modified without sending it again.
+
+use crate::http::{http_date, parse_http_date, Request, Response, StatusCode};
+use crate::router::Handler;
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The validators for a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Works out the validators from a file's size and modification time, the same way nginx
+    /// does. That's much cheaper than hashing the contents, and a file that has been changed
+    /// almost always has a different size or time.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        // HTTP dates only go down to the second, so the time is truncated to match. Otherwise
+        // a file would never look unmodified since the date we sent out for it.
+
+        let last_modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| UNIX_EPOCH + Duration::from_secs(since.as_secs()));
+        let seconds = last_modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", seconds, metadata.len()),
+            last_modified,
+        }
+    }
+
+    /// Whether the copy the client already has is current, going by its If-None-Match or
+    /// If-Modified-Since header. If it sent both, If-None-Match wins, since it's more precise.
+    pub fn is_fresh(&self, request: &Request) -> bool {
+        if request.headers.contains("If-None-Match") {
+            return request
+                .headers
+                .get_all("If-None-Match")
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak_match(tag, &self.etag));
+        }
+
+        match (
+            request
+                .headers
+                .get("If-Modified-Since")
+                .and_then(parse_http_date),
+            self.last_modified,
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Whether a Range request should be honoured. A client resuming a download sends If-Range
+    /// with what it knows about the file, so that if the file has changed since, it gets the
+    /// whole new file rather than the rest of the new one tacked onto the start of the old one.
+    pub fn range_applies(&self, request: &Request) -> bool {
+        let condition = match request.headers.get("If-Range") {
+            Some(condition) => condition.trim(),
+            None => return true,
+        };
+
+        // Only a strong ETag can vouch for every byte being the same.
+
+        if condition.starts_with('"') {
+            condition == self.etag
+        } else if condition.starts_with("W/") {
+            false
+        } else {
+            let date = parse_http_date(condition);
+            date.is_some() && date == self.last_modified
+        }
+    }
+
+    /// Adds ETag and Last-Modified to a response.
+    pub fn apply(&self, response: Response) -> Response {
+        let response = response.header("ETag", &self.etag);
+        match self.last_modified {
+            Some(time) => response.header("Last-Modified", &http_date(time)),
+            None => response,
+        }
+    }
+
+    /// The response for a client whose copy is still fresh.
+    pub fn not_modified(&self) -> Response {
+        self.apply(Response::new(StatusCode::NotModified))
+    }
+}
+
+// Weak comparison ignores the W/ that marks a tag as weak.
+
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// Adds a Cache-Control header to the successful responses of a handler, unless the handler set
+/// one itself. For example, to let browsers keep static files for an hour:
+///
+/// router.get("/static/*path", CacheControl::new("public, max-age=3600", StaticFiles::new(root)))
+pub struct CacheControl<H> {
+    value: String,
+    handler: H,
+}
+
+impl<H: Handler> CacheControl<H> {
+    pub fn new(value: &str, handler: H) -> Self {
+        CacheControl {
+            value: value.to_string(),
+            handler,
+        }
+    }
+}
+
+impl<H: Handler> Handler for CacheControl<H> {
+    fn handle(&self, request: Request) -> Response {
+        let response = self.handler.handle(request);
+
+        // Errors shouldn't be cached for as long as the pages themselves. A 304 has to carry the
+        // same Cache-Control the full response would have.
+
+        let status = response.status.as_u16();
+        let cacheable = (200..300).contains(&status) || response.status == StatusCode::NotModified;
+        if cacheable && !response.headers.contains("Cache-Control") {
+            response.header("Cache-Control", &self.value)
+        } else {
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"5f8c1e2a-9f\"".to_string(),
+            // Sun, 18 Oct 2020 12:34:50 GMT
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_603_024_490)),
+        }
+    }
+
+    #[test]
+    fn checks_whether_the_client_copy_is_fresh() {
+        let validators = validators();
+        let cases = &[
+            ("", false),
+            ("If-None-Match: \"5f8c1e2a-9f\"\r\n", true),
+            ("If-None-Match: \"other\", W/\"5f8c1e2a-9f\"\r\n", true),
+            ("If-None-Match: *\r\n", true),
+            ("If-None-Match: \"other\"\r\n", false),
+            ("If-Modified-Since: Sun, 18 Oct 2020 12:34:50 GMT\r\n", true),
+            (
+                "If-Modified-Since: Sun, 18 Oct 2020 12:34:49 GMT\r\n",
+                false,
+            ),
+            ("If-Modified-Since: never\r\n", false),
+            // If-None-Match wins over If-Modified-Since.
+            (
+                "If-None-Match: \"other\"\r\nIf-Modified-Since: Sun, 18 Oct 2020 12:34:50 GMT\r\n",
+                false,
+            ),
+        ];
+
+        for (headers, fresh) in cases {
+            assert_eq!(
+                validators.is_fresh(&request(headers)),
+                *fresh,
+                "{}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn only_honours_ranges_for_the_same_file() {
+        let validators = validators();
+        let cases = &[
+            ("", true),
+            ("If-Range: \"5f8c1e2a-9f\"\r\n", true),
+            ("If-Range: W/\"5f8c1e2a-9f\"\r\n", false),
+            ("If-Range: \"other\"\r\n", false),
+            ("If-Range: Sun, 18 Oct 2020 12:34:50 GMT\r\n", true),
+            ("If-Range: Sun, 18 Oct 2020 12:34:51 GMT\r\n", false),
+        ];
+
+        for (headers, applies) in cases {
+            assert_eq!(
+                validators.range_applies(&request(headers)),
+                *applies,
+                "{}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn adds_cache_control_to_successful_responses() {
+        let handler = CacheControl::new("max-age=60", |request: Request| match request.path() {
+            "/" => Response::text(StatusCode::Ok, "hi"),
+            "/own" => Response::new(StatusCode::Ok).header("Cache-Control", "no-store"),
+            _ => Response::new(StatusCode::NotFound),
+        });
+        let get = |target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            handler.handle(Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        assert_eq!(get("/").headers.get("Cache-Control"), Some("max-age=60"));
+        assert_eq!(get("/own").headers.get("Cache-Control"), Some("no-store"));
+        assert_eq!(get("/missing").headers.get("Cache-Control"), None);
+    }
+}
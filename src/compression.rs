@@ -0,0 +1,426 @@
+// Compresses responses for clients that can take them compressed. HTML, CSS and JSON tend to come
+// out several times smaller, which makes pages load faster on slow connections.
+//
+// A client lists the encodings it understands, with how much it'd like each one:
+//
+// Accept-Encoding: gzip, deflate;q=0.5, br;q=0.9
+//
+// and we answer with the one we're sending:
+//
+// Content-Encoding: gzip
+//
+// Wrap a router in it to compress everything it serves:
+//
+// Router::new().get("/", index).wrap(Compression::new())
+
+use crate::deflate::{self, Encoder};
+use crate::http::{Body, Headers, Request, Response, StatusCode};
+use crate::middleware::{Middleware, Next};
+use std::io::{self, Read, Write};
+
+/// The encodings we can send, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Picks the encoding to use from a request's Accept-Encoding, if it allows any of ours.
+    pub fn negotiate(headers: &Headers) -> Option<Encoding> {
+        let gzip = quality(headers, "gzip");
+        let deflate = quality(headers, "deflate");
+        if gzip > 0.0 && gzip >= deflate {
+            Some(Encoding::Gzip)
+        } else if deflate > 0.0 {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => deflate::gzip(data),
+            Encoding::Deflate => deflate::zlib(data),
+        }
+    }
+
+    fn encoder<W: Write>(&self, writer: W) -> Encoder<W> {
+        match self {
+            Encoding::Gzip => Encoder::gzip(writer),
+            Encoding::Deflate => Encoder::zlib(writer),
+        }
+    }
+}
+
+// How much the client wants an encoding, from 0 (not at all) to 1. Naming it outright beats a
+// `*`, and an encoding that isn't mentioned at all isn't wanted.
+
+fn quality(headers: &Headers, coding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for item in headers
+        .get_all("Accept-Encoding")
+        .flat_map(|value| value.split(','))
+    {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        } else if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/// Whether a Content-Type is worth compressing. Text is, but images, video and archives are
+/// compressed already, so trying again only wastes time.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+        )
+}
+
+/// Middleware that compresses responses with gzip or deflate. Bodies that are already in memory
+/// are compressed all at once, and the rest, like files, as they're sent.
+pub struct Compression {
+    min_size: u64,
+}
+
+impl Compression {
+    /// Compresses responses of 256 bytes or more.
+    pub fn new() -> Self {
+        Compression { min_size: 256 }
+    }
+
+    /// Sends smaller bodies as they are. Below a few hundred bytes, the gzip header and trailer
+    /// eat up most of what compression would save.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    // Whether we'd compress this response for a client that allows it.
+
+    fn applies_to(&self, response: &Response) -> bool {
+        // A part of a file has to be a part of the same bytes the client would get for the whole
+        // file, so partial content is never compressed.
+
+        if !response.status.allows_body()
+            || response.status == StatusCode::PartialContent
+            || response.headers.contains("Content-Encoding")
+        {
+            return false;
+        }
+
+        response
+            .headers
+            .get("Content-Type")
+            .is_some_and(is_compressible)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let encoding = Encoding::negotiate(&request.headers);
+        let validated: Vec<String> = request
+            .headers
+            .get_all("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().to_string())
+            .collect();
+        let mut response = next.run(request);
+        if response.status == StatusCode::NotModified {
+            return not_modified(response, &validated);
+        }
+        if !self.applies_to(&response) {
+            return response;
+        }
+
+        // Whether we compressed a response depends on the request's Accept-Encoding, so caches
+        // have to keep a copy for each one, even of the responses we didn't compress.
+
+        vary(&mut response);
+
+        // We can't tell how long a body of unknown length is until it's been sent, so it's
+        // compressed in case it's long.
+
+        let encoding = match encoding {
+            Some(encoding) if response.body.len().is_none_or(|len| len >= self.min_size) => {
+                encoding
+            }
+            _ => return response,
+        };
+
+        let body = match std::mem::replace(&mut response.body, Body::Empty) {
+            // Data that's already been compressed some other way can come out bigger, in which
+            // case we send it as it is.
+            Body::Bytes(bytes) => {
+                let compressed = encoding.encode(&bytes);
+                if compressed.len() >= bytes.len() {
+                    return response.body(bytes);
+                }
+                Body::Bytes(compressed)
+            }
+
+            // Anything else could be too big to hold in memory, so it's compressed a piece at a
+            // time while it's being sent, and goes out in chunks.
+            body => Body::writer(move |out| {
+                let mut encoder = encoding.encoder(out);
+                copy(body, &mut encoder)?;
+                encoder.finish().map(drop)
+            }),
+        };
+
+        // The compressed bytes aren't the ones the ETag was made from, so it can only vouch for
+        // the content being the same, and ranges would be of the uncompressed bytes.
+
+        if let Some(weak) = weak_etag(&response) {
+            response.headers.insert("ETag", &weak);
+        }
+        response.headers.remove("Accept-Ranges");
+        response.headers.remove("Content-Length");
+        response
+            .header("Content-Encoding", encoding.as_str())
+            .body(body)
+    }
+}
+
+// Adds Accept-Encoding to a response's Vary, unless it's already covered.
+
+fn vary(response: &mut Response) {
+    if !response.headers.contains_token("Vary", "Accept-Encoding")
+        && !response.headers.contains_token("Vary", "*")
+    {
+        response.headers.append("Vary", "Accept-Encoding");
+    }
+}
+
+// The weak version of a response's ETag, if it has a strong one.
+
+fn weak_etag(response: &Response) -> Option<String> {
+    response
+        .headers
+        .get("ETag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .map(|etag| format!("W/{}", etag))
+}
+
+// A 304 has no body to compress, but it stands in for the response the client already has, and
+// caches update their copy with its headers. So if that copy was compressed, the 304 has to carry
+// the same Vary and weak ETag it did. The 304 itself doesn't tell us whether it was, but we only
+// ever hand out the weak version of a strong ETag along with a compressed body, so a client
+// validating with one has a compressed copy.
+
+fn not_modified(mut response: Response, validated: &[String]) -> Response {
+    if let Some(weak) = weak_etag(&response) {
+        if validated.contains(&weak) {
+            vary(&mut response);
+            response.headers.insert("ETag", &weak);
+        }
+    }
+    response
+}
+
+// Writes the whole of a body to `out`.
+
+fn copy(body: Body, out: &mut dyn Write) -> io::Result<()> {
+    match body {
+        Body::Empty => Ok(()),
+        Body::Bytes(bytes) => out.write_all(&bytes),
+        Body::File(mut file) => io::copy(&mut file, out).map(drop),
+        Body::Sized(reader, length) => io::copy(&mut reader.take(length), out).map(drop),
+        Body::Stream(mut stream) => io::copy(&mut stream, out).map(drop),
+        Body::Writer(write) => write(out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{Handler, Router};
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn negotiates_an_encoding() {
+        let cases = &[
+            ("", None),
+            ("Accept-Encoding: gzip\r\n", Some(Encoding::Gzip)),
+            ("Accept-Encoding: deflate, gzip\r\n", Some(Encoding::Gzip)),
+            (
+                "Accept-Encoding: gzip;q=0.5, deflate\r\n",
+                Some(Encoding::Deflate),
+            ),
+            ("Accept-Encoding: br, *;q=0.1\r\n", Some(Encoding::Gzip)),
+            ("Accept-Encoding: *, gzip;q=0\r\n", Some(Encoding::Deflate)),
+            ("Accept-Encoding: gzip;q=0, deflate;q=0\r\n", None),
+            ("Accept-Encoding: identity\r\n", None),
+        ];
+
+        for (headers, expected) in cases {
+            let request = request(headers);
+            assert_eq!(
+                Encoding::negotiate(&request.headers),
+                *expected,
+                "{}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn recognises_compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/JSON"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+
+    #[test]
+    fn compresses_responses() {
+        let page = "<p>Hello, world!</p>\n".repeat(100);
+        let router = Router::new()
+            .get("/", move |_| {
+                Response::new(StatusCode::Ok)
+                    .header("Content-Type", "text/html")
+                    .header("ETag", "\"1-2\"")
+                    .body(page.as_str())
+            })
+            .wrap(Compression::new());
+
+        let response = router.handle(request("Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"1-2\""));
+        match &response.body {
+            Body::Bytes(bytes) => {
+                assert_eq!(&bytes[..2], &[0x1f, 0x8b]);
+                assert!(bytes.len() < 2100 / 10);
+            }
+            other => panic!("unexpected body {:?}", other),
+        }
+
+        // Clients that don't ask for it still learn that the response varies.
+        let response = router.handle(request(""));
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.len(), Some(2100));
+    }
+
+    #[test]
+    fn keeps_validators_of_compressed_copies_in_step() {
+        let page = "<p>Hello, world!</p>\n".repeat(100);
+        let router = Router::new()
+            .get("/", move |request: Request| {
+                let response = if request.headers.contains("If-None-Match") {
+                    Response::new(StatusCode::NotModified)
+                } else {
+                    Response::new(StatusCode::Ok)
+                        .header("Content-Type", "text/html")
+                        .body(page.as_str())
+                };
+                response.header("ETag", "\"1-2\"")
+            })
+            .wrap(Compression::new());
+
+        let full = router.handle(request("Accept-Encoding: gzip\r\n"));
+        let etag = full.headers.get("ETag").unwrap();
+        let raw = format!("Accept-Encoding: gzip\r\nIf-None-Match: {}\r\n", etag);
+        let response = router.handle(request(&raw));
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert_eq!(response.headers.get("ETag"), Some(etag));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.body.len(), Some(0));
+
+        // A copy that wasn't compressed keeps its strong ETag.
+        let response = router.handle(request("If-None-Match: \"1-2\"\r\n"));
+        assert_eq!(response.headers.get("ETag"), Some("\"1-2\""));
+    }
+
+    #[test]
+    fn compresses_streams_as_they_are_sent() {
+        let page = "<p>Hello, world!</p>\n".repeat(10_000);
+        let router = Router::new()
+            .get("/", move |_| {
+                Response::new(StatusCode::Ok)
+                    .header("Content-Type", "text/html")
+                    .body(Body::stream(io::Cursor::new(page.clone())))
+            })
+            .wrap(Compression::new());
+
+        let response = router.handle(request("Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.body.len(), None);
+        let mut bytes = Vec::new();
+        copy(response.body, &mut bytes).unwrap();
+        assert_eq!(&bytes[..2], &[0x1f, 0x8b]);
+        assert!(bytes.len() < 210_000 / 10);
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let router = Router::new()
+            .get("/png", |_| {
+                Response::new(StatusCode::Ok)
+                    .header("Content-Type", "image/png")
+                    .body(vec![0; 1000])
+            })
+            .get("/small", |_| Response::text(StatusCode::Ok, "hi"))
+            .wrap(Compression::new());
+        let get = |target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", target);
+            router.handle(Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        let response = get("/png");
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), None);
+
+        let response = get("/small");
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.len(), Some(2));
+    }
+}
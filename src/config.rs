@@ -0,0 +1,662 @@
+// Settings for the web server, from the command line and an optional config file.
+//
+// The config file uses a small subset of TOML: one `key = value` per line, where a value is a
+// quoted string, an integer, true or false, or an array of those in square brackets. `#` starts
+// a comment. For example:
+//
+// # Listen on IPv4 and IPv6.
+// bind = ["127.0.0.1:7878", "[::1]:7878"]
+// workers = 8
+// idle_timeout = "5s"
+// document_root = "/srv/www"
+//
+// Every setting can also be given on the command line, as `--idle-timeout 5s` or
+// `--idle-timeout=5s`, and those win over the file.
+
+use crate::access_log::LogFormat;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+    --config FILE            read settings from FILE; options given here override it
+    --check-config           check the settings, and exit without starting the server
+    --bind ADDR              address to listen on; repeat to listen on several [127.0.0.1:7878]
+    --workers N              number of threads handling connections [4]
+    --queue-limit N          connections that can wait for a thread, or \"none\" [none]
+    --max-connections N      connections being handled or waiting at once; any more are
+                             turned away with 503, or \"none\" [256]
+    --retry-after DURATION   how long clients turned away are told to wait [1s]
+    --idle-timeout DURATION  close connections idle for this long [5s]
+    --header-timeout DURATION
+                             how long clients get to send a request's headers [10s]
+    --body-timeout DURATION  how long clients get to send a request's body [30s]
+    --write-timeout DURATION how long a client can keep us waiting to send a response [30s]
+    --shutdown-timeout DURATION
+                             how long open connections get to finish at shutdown [10s]
+    --max-requests N         requests served on one connection before closing it [100]
+    --sleep DURATION         how long /sleep sleeps for [5s]
+    --document-root DIR      directory to serve files from [public]
+    --error-pages DIR        directory with pages for errors, named like 404.html
+                             [the document root]
+    --directory-listings BOOL
+                             list the contents of directories under /static/ that have no
+                             index.html [false]
+    --static-cache-control VALUE
+                             Cache-Control sent with files under /static/
+                             [public, max-age=3600]
+    --access-log FILE        where to write the access log, or \"-\" for stdout [-]
+    --access-log-max-size BYTES
+                             rotate the access log when it grows past this, or 0 for never
+                             [10485760]
+    --log-format FORMAT      access log format: common, combined or json [common]
+    --help                   print this message
+
+Durations are a number followed by ms, s, m or h, e.g. 500ms or 5s.
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    pub workers: usize,
+    /// How many accepted connections can wait for a worker, or None for no limit.
+    pub queue_limit: Option<usize>,
+    /// How many connections can be open at once, whether they're being handled or waiting for a
+    /// worker, or None for no limit.
+    pub max_connections: Option<usize>,
+    pub retry_after: Duration,
+    pub idle_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub max_requests: usize,
+    /// How long the /sleep demo page takes.
+    pub sleep: Duration,
+    pub document_root: PathBuf,
+    /// Where pages for errors, like 404.html, come from, or None for the document root.
+    pub error_pages: Option<PathBuf>,
+    /// Whether directories under /static/ without an index.html are listed.
+    pub directory_listings: bool,
+    /// The Cache-Control header for files served under /static/.
+    pub static_cache_control: String,
+    /// Where the access log goes, or None for stdout.
+    pub access_log: Option<PathBuf>,
+    pub access_log_max_size: u64,
+    pub log_format: LogFormat,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            workers: 4,
+            queue_limit: None,
+            max_connections: Some(256),
+            retry_after: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(10),
+            max_requests: 100,
+            sleep: Duration::from_secs(5),
+            document_root: PathBuf::from("public"),
+            error_pages: None,
+            directory_listings: false,
+            static_cache_control: "public, max-age=3600".to_string(),
+            access_log: None,
+            access_log_max_size: 10 * 1024 * 1024,
+            log_format: LogFormat::Common,
+        }
+    }
+}
+
+/// What the command line asked us to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Serve,
+    CheckConfig,
+    Help,
+}
+
+/// A value from the config file, or from the command line, where everything is a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// A problem with the settings. Problems in a config file say which line they're on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(message: String) -> Self {
+        ConfigError {
+            line: None,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Works out the settings from the command line arguments (without the program name), reading
+    /// the config file if one is given. The settings are validated before they're returned.
+    pub fn from_args<I: IntoIterator<Item = String>>(
+        args: I,
+    ) -> Result<(Config, Mode), ConfigError> {
+        let mut mode = Mode::Serve;
+        let mut file = None;
+        let mut settings: Vec<(String, Value)> = Vec::new();
+        let mut binds = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = arg
+                .strip_prefix("--")
+                .ok_or_else(|| ConfigError::new(format!("unexpected argument {:?}", arg)))?;
+
+            match flag {
+                "help" => mode = Mode::Help,
+                "check-config" => mode = Mode::CheckConfig,
+                _ => {
+                    // Both `--flag value` and `--flag=value` work.
+                    let (name, value) = match flag.find('=') {
+                        Some(eq) => (&flag[..eq], flag[eq + 1..].to_string()),
+                        None => {
+                            let value = args.next().ok_or_else(|| {
+                                ConfigError::new(format!("--{} needs a value", flag))
+                            })?;
+                            (flag, value)
+                        }
+                    };
+
+                    match name {
+                        "config" => file = Some(value),
+                        "bind" => binds.push(Value::String(value)),
+                        _ => settings.push((name.replace('-', "_"), Value::String(value))),
+                    }
+                }
+            }
+        }
+
+        if mode == Mode::Help {
+            return Ok((Config::default(), mode));
+        }
+
+        let mut config = Config::default();
+
+        if let Some(file) = file {
+            let text = fs::read_to_string(&file)
+                .map_err(|err| ConfigError::new(format!("unable to read {}: {}", file, err)))?;
+            for (line, key, value) in parse(&text)? {
+                config.set(&key, &value).map_err(|message| ConfigError {
+                    line: Some(line),
+                    message,
+                })?;
+            }
+        }
+
+        // A --bind on the command line replaces all of the file's addresses, rather than adding
+        // to them, just like every other flag replaces the file's setting.
+
+        if !binds.is_empty() {
+            settings.push(("bind".to_string(), Value::Array(binds)));
+        }
+        for (key, value) in settings {
+            config.set(&key, &value).map_err(|message| {
+                ConfigError::new(format!("--{}: {}", key.replace('_', "-"), message))
+            })?;
+        }
+
+        config.validate()?;
+        Ok((config, mode))
+    }
+
+    /// Changes a setting, using its name in the config file.
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "bind" => {
+                let addrs = match value {
+                    Value::Array(values) => values.iter().map(socket_addr).collect(),
+                    value => socket_addr(value).map(|addr| vec![addr]),
+                }?;
+                self.bind = addrs;
+            }
+            "workers" => self.workers = integer(value)?,
+            "queue_limit" => self.queue_limit = limit(value)?,
+            "max_connections" => self.max_connections = limit(value)?,
+            "retry_after" => self.retry_after = duration(value)?,
+            "idle_timeout" => self.idle_timeout = duration(value)?,
+            "header_timeout" => self.header_timeout = duration(value)?,
+            "body_timeout" => self.body_timeout = duration(value)?,
+            "write_timeout" => self.write_timeout = duration(value)?,
+            "shutdown_timeout" => self.shutdown_timeout = duration(value)?,
+            "max_requests" => self.max_requests = integer(value)?,
+            "sleep" => self.sleep = duration(value)?,
+            "document_root" => self.document_root = PathBuf::from(string(value)?),
+            "error_pages" => self.error_pages = Some(PathBuf::from(string(value)?)),
+            "directory_listings" => self.directory_listings = boolean(value)?,
+            "static_cache_control" => self.static_cache_control = string(value)?.to_string(),
+            "access_log" => {
+                self.access_log = match string(value)? {
+                    "-" => None,
+                    path => Some(PathBuf::from(path)),
+                }
+            }
+            "access_log_max_size" => self.access_log_max_size = integer(value)? as u64,
+            "log_format" => self.log_format = string(value)?.parse()?,
+            _ => return Err(format!("unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Checks that the settings make sense together, and that the document root exists.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let problem = if self.bind.is_empty() {
+            Some("there must be at least one address to bind to".to_string())
+        } else if self.workers == 0 {
+            Some("there must be at least one worker".to_string())
+        } else if [
+            self.idle_timeout,
+            self.header_timeout,
+            self.body_timeout,
+            self.write_timeout,
+        ]
+        .contains(&Duration::from_secs(0))
+        {
+            Some("connection timeouts can't be zero".to_string())
+        } else if self.max_connections == Some(0) {
+            Some("max_connections must be at least 1".to_string())
+        } else if self.max_requests == 0 {
+            Some("max_requests must be at least 1".to_string())
+        } else if !self.document_root.is_dir() {
+            Some(format!(
+                "the document root {} is not a directory",
+                self.document_root.display()
+            ))
+        } else {
+            self.error_pages
+                .as_ref()
+                .filter(|dir| !dir.is_dir())
+                .map(|dir| {
+                    format!(
+                        "the error pages directory {} is not a directory",
+                        dir.display()
+                    )
+                })
+        };
+
+        match problem {
+            Some(message) => Err(ConfigError::new(message)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(format!("expected a string, found {:?}", value)),
+    }
+}
+
+// Integers can be given as strings too, since that's all the command line has.
+
+fn integer(value: &Value) -> Result<usize, String> {
+    let n = match value {
+        Value::Integer(n) => *n,
+        Value::String(s) => s
+            .parse()
+            .map_err(|_| format!("expected a number, found {:?}", s))?,
+        _ => return Err(format!("expected a number, found {:?}", value)),
+    };
+    if n < 0 {
+        return Err(format!("expected a positive number, found {}", n));
+    }
+    Ok(n as usize)
+}
+
+// Booleans can be given as strings too, for the same reason.
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        Value::String(s) if s == "true" => Ok(true),
+        Value::String(s) if s == "false" => Ok(false),
+        _ => Err(format!("expected true or false, found {:?}", value)),
+    }
+}
+
+// A limit is a number, or "none" for no limit at all.
+
+fn limit(value: &Value) -> Result<Option<usize>, String> {
+    match value {
+        Value::String(s) if s == "none" => Ok(None),
+        value => integer(value).map(Some),
+    }
+}
+
+fn socket_addr(value: &Value) -> Result<SocketAddr, String> {
+    let s = string(value)?;
+    s.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("{:?} is not an address, e.g. 127.0.0.1:7878", s))
+}
+
+/// Parses a duration such as `500ms`, `5s`, `2m` or `1h`. A bare integer is a number of seconds.
+pub fn duration(value: &Value) -> Result<Duration, String> {
+    let s = match value {
+        Value::Integer(seconds) if *seconds >= 0 => {
+            return Ok(Duration::from_secs(*seconds as u64))
+        }
+        Value::String(s) => s.trim(),
+        _ => return Err(format!("expected a duration, found {:?}", value)),
+    };
+
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let invalid = || format!("{:?} is not a duration, e.g. 500ms or 5s", s);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+
+    let seconds = |per_unit: u64| {
+        number
+            .checked_mul(per_unit)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("{:?} is too long", s))
+    };
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" | "" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(60 * 60),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a config file into its settings, in order, along with the line each one is on.
+pub fn parse(text: &str) -> Result<Vec<(usize, String, Value)>, ConfigError> {
+    let mut settings: Vec<(usize, String, Value)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let error = |message: String| ConfigError {
+            line: Some(number),
+            message,
+        };
+
+        let mut chars = Chars::new(line);
+        chars.skip_whitespace();
+        if chars.at_end_of_line() {
+            continue;
+        }
+
+        let key = chars.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if key.is_empty() {
+            return Err(error("expected a setting's name".to_string()));
+        }
+        chars.skip_whitespace();
+        if !chars.eat('=') {
+            return Err(error(format!("expected = after {}", key)));
+        }
+        chars.skip_whitespace();
+        let value = chars.value().map_err(error)?;
+        chars.skip_whitespace();
+        if !chars.at_end_of_line() {
+            return Err(error("unexpected text after the value".to_string()));
+        }
+
+        if settings.iter().any(|(_, existing, _)| existing == key) {
+            return Err(error(format!("{} is set more than once", key)));
+        }
+        settings.push((number, key.to_string(), value));
+    }
+
+    Ok(settings)
+}
+
+// A cursor over a line of the config file.
+
+struct Chars<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Chars<'a> {
+    fn new(line: &'a str) -> Self {
+        Chars { line, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.line[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<P: Fn(char) -> bool>(&mut self, predicate: P) -> &'a str {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|&c| predicate(c)) {
+            self.pos += c.len_utf8();
+        }
+        &self.line[start..self.pos]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    // The rest of the line doesn't matter if it's empty or a comment.
+
+    fn at_end_of_line(&self) -> bool {
+        matches!(self.peek(), None | Some('#'))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.string().map(Value::String),
+            Some('[') => self.array(),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let number = self.take_while(|c| c == '-' || c == '_' || c.is_ascii_digit());
+                number
+                    .replace('_', "")
+                    .parse()
+                    .map(Value::Integer)
+                    .map_err(|_| format!("{} is not a number", number))
+            }
+            _ => match self.take_while(|c| c.is_ascii_alphabetic()) {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                "" => Err("expected a value".to_string()),
+                word => Err(format!(
+                    "{} is not a value; strings need to be in quotes",
+                    word
+                )),
+            },
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.eat('"');
+        let mut s = String::new();
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = self.peek().ok_or("unterminated string")?;
+                    self.pos += escaped.len_utf8();
+                    s.push(match escaped {
+                        '"' => '"',
+                        '\\' => '\\',
+                        'n' => '\n',
+                        't' => '\t',
+                        _ => return Err(format!("unknown escape \\{}", escaped)),
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    // Arrays have to fit on one line, which is all our settings need.
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.eat('[');
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if !self.eat(',') {
+                self.skip_whitespace();
+                if !self.eat(']') {
+                    return Err("expected , or ] in array".to_string());
+                }
+                return Ok(Value::Array(values));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_a_config_file() {
+        let text = r#"
+            # Listen on IPv4 and IPv6.
+            bind = ["127.0.0.1:8080", "[::1]:8080",]
+            workers = 8   # one per core
+            idle_timeout = "250ms"
+            log_format = "json"
+            motd = "say \"hi\"\n"
+        "#;
+
+        let settings = parse(text).unwrap();
+        assert_eq!(
+            settings[0],
+            (
+                3,
+                "bind".to_string(),
+                Value::Array(vec![
+                    Value::String("127.0.0.1:8080".to_string()),
+                    Value::String("[::1]:8080".to_string()),
+                ])
+            )
+        );
+        assert_eq!(settings[1].2, Value::Integer(8));
+        assert_eq!(settings[4].2, Value::String("say \"hi\"\n".to_string()));
+
+        let mut config = Config::default();
+        for (_, key, value) in &settings[..4] {
+            config.set(key, value).unwrap();
+        }
+        assert_eq!(config.bind.len(), 2);
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.idle_timeout, Duration::from_millis(250));
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn reports_mistakes_with_their_line() {
+        let cases = &[
+            ("workers 8", "line 1: expected = after workers"),
+            (
+                "\nroot = public",
+                "line 2: public is not a value; strings need to be in quotes",
+            ),
+            ("a = \"open", "line 1: unterminated string"),
+            ("a = [1 2]", "line 1: expected , or ] in array"),
+            ("a = 1\na = 2", "line 2: a is set more than once"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(parse(text).unwrap_err().to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let dir = TempDir::new("server-config");
+        let file = dir.join("server.toml");
+        fs::write(
+            &file,
+            "bind = [\"127.0.0.1:1\", \"127.0.0.1:2\"]\nworkers = 2\nqueue_limit = 10\n",
+        )
+        .unwrap();
+
+        let (config, mode) = Config::from_args(args(&[
+            "--config",
+            file.to_str().unwrap(),
+            "--workers=6",
+            "--bind",
+            "127.0.0.1:3",
+            "--document-root",
+            ".",
+            "--check-config",
+        ]))
+        .unwrap();
+
+        assert_eq!(mode, Mode::CheckConfig);
+        assert_eq!(config.bind, vec![SocketAddr::from(([127, 0, 0, 1], 3))]);
+        assert_eq!(config.workers, 6);
+        assert_eq!(config.queue_limit, Some(10));
+
+        let err = Config::from_args(args(&["--document-root", ".", "--workers", "0"]));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "there must be at least one worker"
+        );
+        let err = Config::from_args(args(&["--idle-timeout", "soon"]));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "--idle-timeout: \"soon\" is not a duration, e.g. 500ms or 5s"
+        );
+        let err = Config::from_args(args(&["--idle-timeout", "999999999999999999h"]));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "--idle-timeout: \"999999999999999999h\" is too long"
+        );
+    }
+}
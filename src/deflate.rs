@@ -0,0 +1,1066 @@
+// A DEFLATE compressor (RFC 1951), with the gzip (RFC 1952) and zlib (RFC 1950) wrappers that HTTP
+// calls `gzip` and `deflate`.
+//
+// Compression happens in two steps. First, repeated strings are replaced with references back to
+// an earlier copy ("the next 12 bytes are the same as the ones 340 bytes ago"), which is what
+// makes text shrink so well. Then the literal bytes and references are written with Huffman
+// codes, where common symbols get shorter codes than rare ones. Each block picks whichever of
+// its own Huffman codes, the fixed codes from the spec, or no compression at all, comes out
+// smallest.
+//
+// There's no decompressor: we only ever need to send compressed data, not read it.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+// The gzip header has no file name or modification time, since a response has neither. 255
+// means "unknown operating system".
+
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+
+// 0x78: DEFLATE with a 32K window. 0x9c: the default compression level, plus a check so that the
+// two bytes together are a multiple of 31.
+
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x9c];
+
+/// Compresses `data` into a gzip file, as sent with `Content-Encoding: gzip`.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = GZIP_HEADER.to_vec();
+    out.extend(deflate(data));
+    out.extend(&crc32(data).to_le_bytes());
+    out.extend(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compresses `data` into a zlib stream, as sent with `Content-Encoding: deflate`.
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = ZLIB_HEADER.to_vec();
+    out.extend(deflate(data));
+    out.extend(&adler32(data).to_be_bytes());
+    out
+}
+
+/// The CRC-32 checksum gzip uses to catch corrupted data.
+pub fn crc32(data: &[u8]) -> u32 {
+    update_crc32(0, data)
+}
+
+// The CRC of every possible byte, so we can go a byte at a time rather than a bit at a time.
+// It's worked out at compile time.
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+// Carries on a CRC-32 from the data before, so a stream can be checksummed a piece at a time.
+
+fn update_crc32(crc: u32, data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!crc, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+/// The Adler-32 checksum zlib uses to catch corrupted data.
+pub fn adler32(data: &[u8]) -> u32 {
+    update_adler32(1, data)
+}
+
+fn update_adler32(adler: u32, data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+
+    // 5552 is the most bytes we can add up before `b` could overflow, so we only need to take the
+    // remainder once per chunk.
+
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+/// Compresses `data` into raw DEFLATE blocks, without any wrapper.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    let tokens = Matcher::new(data).tokens(0);
+    write_blocks(&mut writer, &tokens, data, true);
+    writer.finish()
+}
+
+// Writes the tokens for `data` as blocks. Each block gets its own Huffman codes, so splitting the
+// data up lets the codes follow along as it changes. A block also has to be small enough to be
+// sent as it is, which caps it at 65535 bytes.
+
+fn write_blocks(writer: &mut BitWriter, tokens: &[Token], data: &[u8], last: bool) {
+    if tokens.is_empty() {
+        // Even nothing at all needs a block to say so.
+        if last {
+            write_block(writer, &[], &[], true);
+        }
+        return;
+    }
+
+    let mut start = 0;
+    let mut position = 0;
+    let mut block_start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let length = token.len();
+        let full = i - start >= BLOCK_TOKENS || position + length - block_start > MAX_STORED;
+        if full {
+            write_block(
+                writer,
+                &tokens[start..i],
+                &data[block_start..position],
+                false,
+            );
+            start = i;
+            block_start = position;
+        }
+        position += length;
+    }
+    write_block(writer, &tokens[start..], &data[block_start..position], last);
+}
+
+/// Compresses data as it's written, for when there's too much to compress all at once. The data
+/// is compressed a segment at a time, and matches can reach back into the segment before, so it
+/// comes out nearly as small as compressing it in one go.
+pub struct Encoder<W: Write> {
+    writer: W,
+    format: Format,
+    // The end of what's already been compressed, which later matches can refer back to, followed
+    // by what's waiting to be compressed, from `pending` on.
+    buffer: Vec<u8>,
+    pending: usize,
+    bits: BitWriter,
+    checksum: u32,
+    length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Gzip,
+    Zlib,
+}
+
+// How much data we collect before compressing it.
+
+const SEGMENT: usize = 64 * 1024;
+
+impl<W: Write> Encoder<W> {
+    /// Writes a gzip file, like `gzip`, to `writer`.
+    pub fn gzip(writer: W) -> Self {
+        let mut encoder = Encoder::new(writer, Format::Gzip, 0);
+        encoder.bits.write_bytes(&GZIP_HEADER);
+        encoder
+    }
+
+    /// Writes a zlib stream, like `zlib`, to `writer`.
+    pub fn zlib(writer: W) -> Self {
+        let mut encoder = Encoder::new(writer, Format::Zlib, 1);
+        encoder.bits.write_bytes(&ZLIB_HEADER);
+        encoder
+    }
+
+    fn new(writer: W, format: Format, checksum: u32) -> Self {
+        Encoder {
+            writer,
+            format,
+            buffer: Vec::new(),
+            pending: 0,
+            bits: BitWriter::default(),
+            checksum,
+            length: 0,
+        }
+    }
+
+    /// Compresses whatever is left, writes the trailer, and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress(true);
+        self.bits.align();
+        match self.format {
+            Format::Gzip => {
+                self.bits.write_bytes(&self.checksum.to_le_bytes());
+                self.bits.write_bytes(&self.length.to_le_bytes());
+            }
+            Format::Zlib => self.bits.write_bytes(&self.checksum.to_be_bytes()),
+        }
+        self.writer.write_all(&self.bits.take())?;
+        Ok(self.writer)
+    }
+
+    // Compresses the pending data into blocks, and keeps the end of it around for the next
+    // segment's matches to refer back to.
+
+    fn compress(&mut self, last: bool) {
+        let tokens = Matcher::new(&self.buffer).tokens(self.pending);
+        write_blocks(&mut self.bits, &tokens, &self.buffer[self.pending..], last);
+
+        let keep = self.buffer.len().saturating_sub(WINDOW);
+        self.buffer.drain(..keep);
+        self.pending = self.buffer.len();
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.checksum = match self.format {
+            Format::Gzip => update_crc32(self.checksum, buf),
+            Format::Zlib => update_adler32(self.checksum, buf),
+        };
+        self.length = self.length.wrapping_add(buf.len() as u32);
+        self.buffer.extend_from_slice(buf);
+
+        if self.buffer.len() - self.pending >= SEGMENT {
+            self.compress(false);
+            self.writer.write_all(&self.bits.take())?;
+        }
+        Ok(buf.len())
+    }
+
+    // Everything written so far has to reach the other end, even the last few bits of a block,
+    // whether they're from the data that's pending or were left over by the last segment. An
+    // empty stored block ends on a byte boundary, so it pushes them out.
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.len() > self.pending {
+            self.compress(false);
+        }
+        if self.bits.count > 0 {
+            self.bits.write(0, 3);
+            self.bits.write_bytes(&[0, 0, 0xff, 0xff]);
+        }
+        self.writer.write_all(&self.bits.take())?;
+        self.writer.flush()
+    }
+}
+
+const BLOCK_TOKENS: usize = 16 * 1024;
+const MAX_STORED: usize = 65535;
+
+// The window is how far back a reference can reach, and matches are between 3 and 258 bytes long.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// How many earlier positions we try before settling for the best match so far. More finds longer
+// matches, but takes longer.
+
+const MAX_CHAIN: usize = 128;
+const HASH_BITS: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+impl Token {
+    // How many bytes of the original data the token stands for.
+    fn len(&self) -> usize {
+        match self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => *length as usize,
+        }
+    }
+}
+
+// Finds the repeated strings. Every position is filed under a hash of the three bytes that start
+// there, so we only compare against earlier positions that start the same way. `head` holds the
+// most recent position for each hash, and `prev` chains each position to the one before it with
+// the same hash. Positions are stored plus one, so that zero can mean "none".
+
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Matcher {
+            data,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW],
+        }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.data[position..position + MIN_MATCH];
+        let hash = (bytes[0] as usize) << 10 ^ (bytes[1] as usize) << 5 ^ bytes[2] as usize;
+        hash & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(position);
+        self.prev[position % WINDOW] = self.head[hash];
+        self.head[hash] = position as u32 + 1;
+    }
+
+    // The longest earlier copy of the bytes at `position`, as (length, distance).
+    fn longest_match(&self, position: usize) -> (usize, usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return (0, 0);
+        }
+
+        let max = (self.data.len() - position).min(MAX_MATCH);
+        let target = &self.data[position..position + max];
+        let (mut best_length, mut best_distance) = (0, 0);
+
+        let mut candidate = self.head[self.hash(position)] as usize;
+        for _ in 0..MAX_CHAIN {
+            if candidate == 0 {
+                break;
+            }
+            let start = candidate - 1;
+            if position - start > WINDOW {
+                break;
+            }
+
+            let length = self.data[start..]
+                .iter()
+                .zip(target)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best_length {
+                best_length = length;
+                best_distance = position - start;
+                if length == max {
+                    break;
+                }
+            }
+
+            // A slot in `prev` that has been reused for a later position would send us forwards,
+            // so only ever follow the chain backwards.
+
+            let next = self.prev[start % WINDOW] as usize;
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        if best_length >= MIN_MATCH {
+            (best_length, best_distance)
+        } else {
+            (0, 0)
+        }
+    }
+
+    // The tokens for the data from `start` on. What comes before it is only there to be referred
+    // back to.
+
+    fn tokens(mut self, start: usize) -> Vec<Token> {
+        for position in start.saturating_sub(WINDOW)..start {
+            self.insert(position);
+        }
+
+        let mut tokens = Vec::new();
+        let mut position = start;
+
+        while position < self.data.len() {
+            let (length, distance) = self.longest_match(position);
+            self.insert(position);
+
+            if length == 0 {
+                tokens.push(Token::Literal(self.data[position]));
+                position += 1;
+                continue;
+            }
+
+            // If the match starting at the next byte is longer, it's worth sending this byte on
+            // its own to get it.
+
+            if length < MAX_MATCH && self.longest_match(position + 1).0 > length {
+                tokens.push(Token::Literal(self.data[position]));
+                position += 1;
+                continue;
+            }
+
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+            for skipped in position + 1..position + length {
+                self.insert(skipped);
+            }
+            position += length;
+        }
+
+        tokens
+    }
+}
+
+// Lengths and distances are sent as a symbol for a range of values, followed by extra bits that
+// pick one value out of the range. These are the first value of each range, and how many extra
+// bits it has.
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The range a value falls in is the last one starting at or before it.
+
+fn range_of(bases: &[u16], value: u16) -> usize {
+    bases.iter().rposition(|&base| base <= value).unwrap()
+}
+
+const END_OF_BLOCK: usize = 256;
+const LITERAL_SYMBOLS: usize = 286;
+const DISTANCE_SYMBOLS: usize = 30;
+
+// The code lengths that describe a block's Huffman codes are themselves Huffman coded, and their
+// code lengths are sent in this order, so that the ones most likely to be unused come last and
+// can be left off.
+
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn write_block(writer: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let mut literal_counts = [0u32; LITERAL_SYMBOLS];
+    let mut distance_counts = [0u32; DISTANCE_SYMBOLS];
+    literal_counts[END_OF_BLOCK] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_counts[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                literal_counts[257 + range_of(&LENGTH_BASE, length)] += 1;
+                distance_counts[range_of(&DISTANCE_BASE, distance)] += 1;
+            }
+        }
+    }
+
+    // Work out how big the block would be each way, and use the smallest.
+
+    let literal_lengths = code_lengths(&literal_counts, 15);
+    let mut distance_lengths = code_lengths(&distance_counts, 15);
+    if distance_lengths.iter().all(|&length| length == 0) {
+        // A block without any references still has to describe a distance code.
+        distance_lengths[0] = 1;
+    }
+    let header = DynamicHeader::new(&literal_lengths, &distance_lengths);
+    let dynamic_size = 3
+        + header.size()
+        + symbols_size(&literal_counts, &literal_lengths, literal_extra)
+        + symbols_size(&distance_counts, &distance_lengths, distance_extra);
+
+    let (fixed_literals, fixed_distances) = fixed_lengths();
+    let fixed_size = 3
+        + symbols_size(&literal_counts, &fixed_literals, literal_extra)
+        + symbols_size(&distance_counts, &fixed_distances, distance_extra);
+
+    // Padding up to a byte, then the length and its complement.
+    let stored_size = 3 + 7 + 32 + 8 * raw.len() as u64;
+
+    writer.write(last as u32, 1);
+    if stored_size <= dynamic_size.min(fixed_size) {
+        writer.write(0b00, 2);
+        writer.align();
+        writer.write(raw.len() as u32, 16);
+        writer.write(!(raw.len() as u32) & 0xffff, 16);
+        writer.write_bytes(raw);
+    } else if fixed_size <= dynamic_size {
+        writer.write(0b01, 2);
+        let literals = Huffman::new(&fixed_literals);
+        let distances = Huffman::new(&fixed_distances);
+        write_tokens(writer, tokens, &literals, &distances);
+    } else {
+        writer.write(0b10, 2);
+        header.write(writer);
+        let literals = Huffman::new(&literal_lengths);
+        let distances = Huffman::new(&distance_lengths);
+        write_tokens(writer, tokens, &literals, &distances);
+    }
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], literals: &Huffman, distances: &Huffman) {
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literals.write(writer, byte as usize),
+            Token::Match { length, distance } => {
+                let range = range_of(&LENGTH_BASE, length);
+                literals.write(writer, 257 + range);
+                writer.write(
+                    (length - LENGTH_BASE[range]) as u32,
+                    LENGTH_EXTRA[range] as u32,
+                );
+
+                let range = range_of(&DISTANCE_BASE, distance);
+                distances.write(writer, range);
+                writer.write(
+                    (distance - DISTANCE_BASE[range]) as u32,
+                    DISTANCE_EXTRA[range] as u32,
+                );
+            }
+        }
+    }
+    literals.write(writer, END_OF_BLOCK);
+}
+
+// How many bits the symbols take with these code lengths, including the extra bits that follow
+// them.
+
+fn symbols_size(counts: &[u32], lengths: &[u8], extra: impl Fn(usize) -> u8) -> u64 {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(symbol, &count)| count as u64 * (lengths[symbol] + extra(symbol)) as u64)
+        .sum()
+}
+
+fn literal_extra(symbol: usize) -> u8 {
+    if symbol > END_OF_BLOCK {
+        LENGTH_EXTRA[symbol - 257]
+    } else {
+        0
+    }
+}
+
+fn distance_extra(symbol: usize) -> u8 {
+    DISTANCE_EXTRA[symbol]
+}
+
+// The codes the spec defines for blocks that don't bring their own.
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let literals = (0..288)
+        .map(|symbol| match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        })
+        .collect();
+    (literals, vec![5; DISTANCE_SYMBOLS])
+}
+
+// Works out the Huffman code length for each symbol from how often it's used, none longer than
+// `max_bits`. Unused symbols get a length of zero, meaning no code at all.
+
+fn code_lengths(counts: &[u32], max_bits: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; counts.len()];
+    let mut used: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] > 0).collect();
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        }
+        _ => {}
+    }
+
+    // Build the Huffman tree by repeatedly joining the two least used nodes, then a symbol's code
+    // length is how deep its leaf ends up.
+
+    let mut parents = vec![0usize; used.len() * 2 - 1];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = used
+        .iter()
+        .enumerate()
+        .map(|(node, &symbol)| Reverse((counts[symbol] as u64, node)))
+        .collect();
+    let mut next = used.len();
+    while heap.len() > 1 {
+        let Reverse((weight_a, a)) = heap.pop().unwrap();
+        let Reverse((weight_b, b)) = heap.pop().unwrap();
+        parents[a] = next;
+        parents[b] = next;
+        heap.push(Reverse((weight_a + weight_b, next)));
+        next += 1;
+    }
+
+    // Parents always come after their children, so walking backwards from the root fills in each
+    // node's depth before its children need it.
+
+    let root = next - 1;
+    let mut depths = vec![0usize; next];
+    for node in (0..root).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+
+    // If the tree is too deep, move leaves up until it fits. Taking a leaf from the bottom and
+    // hanging it, with another leaf from the bottom, below a leaf from higher up keeps the code
+    // complete. Then the shortest codes go back to the most used symbols.
+
+    let max_bits = max_bits as usize;
+    let mut per_length = vec![0usize; depths.iter().max().unwrap().max(&max_bits) + 1];
+    for &depth in &depths[..used.len()] {
+        per_length[depth.min(max_bits)] += 1;
+    }
+    let kraft = |per_length: &[usize]| -> usize {
+        (1..=max_bits)
+            .map(|length| per_length[length] << (max_bits - length))
+            .sum()
+    };
+    let mut total = kraft(&per_length);
+    while total > 1 << max_bits {
+        per_length[max_bits] -= 1;
+        for length in (1..max_bits).rev() {
+            if per_length[length] > 0 {
+                per_length[length] -= 1;
+                per_length[length + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+
+    used.sort_by_key(|&symbol| Reverse(counts[symbol]));
+    let mut symbols = used.into_iter();
+    for (length, &count) in per_length.iter().enumerate().take(max_bits + 1) {
+        for symbol in symbols.by_ref().take(count) {
+            lengths[symbol] = length as u8;
+        }
+    }
+    lengths
+}
+
+// The header of a block with its own codes: how many of each kind of code there are, and then
+// their lengths. Runs of the same length are shortened with three special symbols, and the
+// result is Huffman coded in turn.
+
+struct DynamicHeader {
+    literal_count: usize,
+    distance_count: usize,
+    /// The lengths, as (symbol, extra bits) with runs shortened.
+    symbols: Vec<(u8, u8)>,
+    lengths: Vec<u8>,
+    length_count: usize,
+}
+
+impl DynamicHeader {
+    fn new(literal_lengths: &[u8], distance_lengths: &[u8]) -> Self {
+        // Trailing unused codes can be left off, down to the minimum counts the format allows.
+
+        let used = |lengths: &[u8], min: usize| {
+            lengths
+                .iter()
+                .rposition(|&l| l > 0)
+                .map_or(min, |i| (i + 1).max(min))
+        };
+        let literal_count = used(literal_lengths, 257);
+        let distance_count = used(distance_lengths, 1);
+
+        let all: Vec<u8> = literal_lengths[..literal_count]
+            .iter()
+            .chain(&distance_lengths[..distance_count])
+            .copied()
+            .collect();
+
+        // 16 repeats the previous length 3-6 times, 17 is 3-10 zeros and 18 is 11-138 zeros.
+
+        let mut symbols = Vec::new();
+        let mut i = 0;
+        while i < all.len() {
+            let length = all[i];
+            let run = all[i..].iter().take_while(|&&l| l == length).count();
+            if length == 0 && run >= 11 {
+                let run = run.min(138);
+                symbols.push((18, (run - 11) as u8));
+                i += run;
+            } else if length == 0 && run >= 3 {
+                let run = run.min(10);
+                symbols.push((17, (run - 3) as u8));
+                i += run;
+            } else if length != 0 && run >= 4 {
+                symbols.push((length, 0));
+                let run = (run - 1).min(6);
+                symbols.push((16, (run - 3) as u8));
+                i += run + 1;
+            } else {
+                symbols.push((length, 0));
+                i += 1;
+            }
+        }
+
+        let mut counts = [0u32; 19];
+        for &(symbol, _) in &symbols {
+            counts[symbol as usize] += 1;
+        }
+        let lengths = code_lengths(&counts, 7);
+        let length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| lengths[symbol] > 0)
+            .map_or(4, |i| (i + 1).max(4));
+
+        DynamicHeader {
+            literal_count,
+            distance_count,
+            symbols,
+            lengths,
+            length_count,
+        }
+    }
+
+    fn extra_bits(symbol: u8) -> u64 {
+        match symbol {
+            16 => 2,
+            17 => 3,
+            18 => 7,
+            _ => 0,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        let symbols: u64 = self
+            .symbols
+            .iter()
+            .map(|&(symbol, _)| self.lengths[symbol as usize] as u64 + Self::extra_bits(symbol))
+            .sum();
+        5 + 5 + 4 + 3 * self.length_count as u64 + symbols
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write((self.literal_count - 257) as u32, 5);
+        writer.write((self.distance_count - 1) as u32, 5);
+        writer.write((self.length_count - 4) as u32, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..self.length_count] {
+            writer.write(self.lengths[symbol] as u32, 3);
+        }
+
+        let code = Huffman::new(&self.lengths);
+        for &(symbol, extra) in &self.symbols {
+            code.write(writer, symbol as usize);
+            writer.write(extra as u32, Self::extra_bits(symbol) as u32);
+        }
+    }
+}
+
+// The codes themselves follow from the lengths alone: shorter codes come first, and codes of the
+// same length are in symbol order. That's why only the lengths need to be sent.
+
+struct Huffman {
+    codes: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut per_length = [0u16; 16];
+        for &length in lengths {
+            per_length[length as usize] += 1;
+        }
+        per_length[0] = 0;
+
+        let mut next = [0u16; 16];
+        let mut code = 0;
+        for length in 1..16 {
+            code = (code + per_length[length - 1]) << 1;
+            next[length] = code;
+        }
+
+        // Huffman codes are packed starting from their most significant bit, while everything
+        // else is packed starting from the least significant, so store them reversed.
+
+        let codes = lengths
+            .iter()
+            .map(|&length| {
+                if length == 0 {
+                    return (0, 0);
+                }
+                let code = next[length as usize];
+                next[length as usize] += 1;
+                (code.reverse_bits() >> (16 - length), length)
+            })
+            .collect();
+        Huffman { codes }
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: usize) {
+        let (code, length) = self.codes[symbol];
+        writer.write(code as u32, length as u32);
+    }
+}
+
+// DEFLATE packs bits into bytes starting from the least significant bit.
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.align();
+        self.out.extend_from_slice(bytes);
+    }
+
+    // The whole bytes written so far. Any bits left over stay behind for the next write.
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Just enough of a decompressor to check that ours round-trips.
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: usize) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let byte = self.data[self.position / 8];
+                value |= ((byte >> (self.position % 8)) as u32 & 1) << i;
+                self.position += 1;
+            }
+            value
+        }
+
+        fn symbol(&mut self, decoder: &HashMap<(u16, u8), usize>) -> usize {
+            let (mut code, mut length) = (0u16, 0u8);
+            loop {
+                code |= (self.bits(1) as u16) << length;
+                length += 1;
+                if let Some(&symbol) = decoder.get(&(code, length)) {
+                    return symbol;
+                }
+            }
+        }
+    }
+
+    fn decoder(lengths: &[u8]) -> HashMap<(u16, u8), usize> {
+        let codes = Huffman::new(lengths).codes;
+        (0..codes.len())
+            .filter(|&symbol| codes[symbol].1 > 0)
+            .map(|symbol| (codes[symbol], symbol))
+            .collect()
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, position: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            match reader.bits(2) {
+                0 => {
+                    reader.position = reader.position.div_ceil(8) * 8;
+                    let length = reader.bits(16) as usize;
+                    assert_eq!(reader.bits(16) as usize, !length & 0xffff);
+                    let start = reader.position / 8;
+                    out.extend_from_slice(&data[start..start + length]);
+                    reader.position += length * 8;
+                }
+                kind => {
+                    let (literals, distances) = if kind == 1 {
+                        fixed_lengths()
+                    } else {
+                        let literal_count = reader.bits(5) as usize + 257;
+                        let distance_count = reader.bits(5) as usize + 1;
+                        let length_count = reader.bits(4) as usize + 4;
+                        let mut code_lengths = vec![0u8; 19];
+                        for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+                            code_lengths[symbol] = reader.bits(3) as u8;
+                        }
+                        let code_lengths = decoder(&code_lengths);
+                        let mut all = Vec::new();
+                        while all.len() < literal_count + distance_count {
+                            match reader.symbol(&code_lengths) {
+                                16 => {
+                                    let previous = *all.last().unwrap();
+                                    let run = 3 + reader.bits(2) as usize;
+                                    all.extend(vec![previous; run]);
+                                }
+                                17 => all.extend(vec![0; 3 + reader.bits(3) as usize]),
+                                18 => all.extend(vec![0; 11 + reader.bits(7) as usize]),
+                                length => all.push(length as u8),
+                            }
+                        }
+                        let distances = all.split_off(literal_count);
+                        (all, distances)
+                    };
+                    let (literals, distances) = (decoder(&literals), decoder(&distances));
+
+                    loop {
+                        let symbol = reader.symbol(&literals);
+                        if symbol < 256 {
+                            out.push(symbol as u8);
+                        } else if symbol == END_OF_BLOCK {
+                            break;
+                        } else {
+                            let range = symbol - 257;
+                            let length = LENGTH_BASE[range] as usize
+                                + reader.bits(LENGTH_EXTRA[range] as usize) as usize;
+                            let range = reader.symbol(&distances);
+                            let distance = DISTANCE_BASE[range] as usize
+                                + reader.bits(DISTANCE_EXTRA[range] as usize) as usize;
+                            for _ in 0..length {
+                                out.push(out[out.len() - distance]);
+                            }
+                        }
+                    }
+                }
+            }
+            if last {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn computes_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn round_trips() {
+        // A little pseudo-random generator, so the noise is the same every run.
+        let mut state = 12345u32;
+        let mut noise = || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        };
+
+        let text = "<li><a href=\"/posts/1\">Hello, world!</a></li>\n".repeat(2000);
+        let samples: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabc".to_vec(),
+            vec![0; 100_000],
+            text.clone().into_bytes(),
+            (0..70_000).map(|_| noise()).collect(),
+            (0..50_000).map(|_| noise() % 4).collect(),
+        ];
+
+        for sample in &samples {
+            let compressed = deflate(sample);
+            assert_eq!(&inflate(&compressed), sample, "{} bytes", sample.len());
+        }
+
+        // Text this repetitive should shrink to almost nothing.
+        assert!(deflate(text.as_bytes()).len() < text.len() / 20);
+    }
+
+    #[test]
+    fn wraps_in_gzip_and_zlib() {
+        let data = b"hello hello hello hello";
+
+        let gzip = gzip(data);
+        assert_eq!(&gzip[..3], &[0x1f, 0x8b, 8]);
+        let (body, trailer) = gzip[10..].split_at(gzip.len() - 18);
+        assert_eq!(inflate(body), data);
+        assert_eq!(trailer[..4], crc32(data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+
+        let zlib = zlib(data);
+        assert_eq!((zlib[0] as u32 * 256 + zlib[1] as u32) % 31, 0);
+        let (body, trailer) = zlib[2..].split_at(zlib.len() - 6);
+        assert_eq!(inflate(body), data);
+        assert_eq!(trailer, adler32(data).to_be_bytes());
+    }
+
+    #[test]
+    fn compresses_streams() {
+        let text = "<li><a href=\"/posts/1\">Hello, world!</a></li>\n".repeat(5000);
+        let data = text.as_bytes();
+
+        // Written in uneven pieces, with a flush part way through.
+        let mut encoder = Encoder::gzip(Vec::new());
+        for (i, piece) in data.chunks(7000).enumerate() {
+            encoder.write_all(piece).unwrap();
+            if i == 3 {
+                encoder.flush().unwrap();
+            }
+        }
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(&gzip[..10], &GZIP_HEADER);
+        let (body, trailer) = gzip[10..].split_at(gzip.len() - 18);
+        assert_eq!(inflate(body), data);
+        assert_eq!(trailer[..4], crc32(data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+
+        // Matches reach back across segments, so it's about as small as compressing it at once.
+        assert!(gzip.len() < deflate(data).len() * 2);
+
+        // A segment compressed by a write can leave a few bits behind, which a flush with nothing
+        // pending still has to push out.
+        let mut encoder = Encoder::gzip(Vec::new());
+        encoder.write_all(&data[..SEGMENT]).unwrap();
+        assert!(encoder.bits.count > 0);
+        encoder.flush().unwrap();
+        assert_eq!(encoder.bits.count, 0);
+        assert!(encoder.writer.ends_with(&[0, 0, 0xff, 0xff]));
+        encoder.write_all(&data[SEGMENT..]).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(inflate(&gzip[10..gzip.len() - 8]), data);
+
+        let mut encoder = Encoder::zlib(Vec::new());
+        encoder.write_all(b"").unwrap();
+        let zlib = encoder.finish().unwrap();
+        assert_eq!(zlib, self::zlib(b""));
+    }
+}
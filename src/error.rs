@@ -0,0 +1,267 @@
+// Errors that happen while serving a request, and the pages sent for them.
+//
+// Whatever goes wrong, whether reading the request, running the handler or reading a file for
+// it, the client gets a response with a status code that says whose fault it was: 4xx for the
+// client's, 5xx for ours. The body of that response comes from `ErrorPages`, which fills in any
+// error response that doesn't have a body of its own:
+//
+// Router::new()
+//     .get("/", fallible(|_| Ok(Response::text(StatusCode::Ok, &fs::read_to_string("a.txt")?))))
+//     .wrap(ErrorPages::new().directory("errors"))
+//
+// answers with errors/404.html if a.txt doesn't exist, and errors/500.html if it can't be read.
+
+use crate::http::{ReadError, Request, Response, StatusCode};
+use crate::middleware::{Middleware, Next};
+use crate::router::Handler;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// Reading or writing a file or the connection failed.
+    Io(io::Error),
+    /// The request couldn't be read, because it was malformed, too large or too slow.
+    Request(ReadError),
+    /// A handler couldn't answer the request.
+    Handler { status: StatusCode, message: String },
+}
+
+impl ServerError {
+    /// A handler error that's our fault rather than the client's, like a missing template.
+    pub fn internal<M: fmt::Display>(message: M) -> Self {
+        ServerError::Handler {
+            status: StatusCode::InternalServerError,
+            message: message.to_string(),
+        }
+    }
+
+    /// The status code to answer with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ServerError::Io(err) => match err.kind() {
+                io::ErrorKind::NotFound => StatusCode::NotFound,
+                io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+                _ => StatusCode::InternalServerError,
+            },
+            ServerError::Request(err) => err.status_code(),
+            ServerError::Handler { status, .. } => *status,
+        }
+    }
+
+    /// Whether the error is the client going away, which happens all the time and isn't a
+    /// problem with the server.
+    pub fn is_disconnect(&self) -> bool {
+        let err = match self {
+            ServerError::Io(err) | ServerError::Request(ReadError::Io(err)) => err,
+            ServerError::Request(ReadError::UnexpectedEof) => return true,
+            _ => return false,
+        };
+        matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(err) => err.fmt(f),
+            ServerError::Request(err) => err.fmt(f),
+            ServerError::Handler { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServerError::Io(err) => Some(err),
+            ServerError::Request(err) => Some(err),
+            ServerError::Handler { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        ServerError::Io(err)
+    }
+}
+
+impl From<ReadError> for ServerError {
+    fn from(err: ReadError) -> Self {
+        ServerError::Request(err)
+    }
+}
+
+/// The response for an error is just its status. `ErrorPages` gives it a body.
+impl From<ServerError> for Response {
+    fn from(err: ServerError) -> Self {
+        Response::new(err.status_code())
+    }
+}
+
+/// A handler that can fail, so that it can use `?`. Errors are answered with their status code,
+/// and the ones that are our fault are logged.
+pub struct Fallible<F>(F);
+
+pub fn fallible<F>(handler: F) -> Fallible<F>
+where
+    F: Fn(Request) -> Result<Response, ServerError> + Send + Sync + 'static,
+{
+    Fallible(handler)
+}
+
+impl<F> Handler for Fallible<F>
+where
+    F: Fn(Request) -> Result<Response, ServerError> + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        let target = request.target.clone();
+        (self.0)(request).unwrap_or_else(|err| {
+            if err.status_code().as_u16() >= 500 {
+                eprintln!("unable to answer {}: {}", target, err);
+            }
+            Response::from(err)
+        })
+    }
+}
+
+/// The pages sent with error responses. Used as middleware, it fills in the body of every error
+/// response that doesn't have one.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    dir: Option<PathBuf>,
+}
+
+impl ErrorPages {
+    /// Plain text pages that just give the status, like `404 Not Found`.
+    pub fn new() -> Self {
+        ErrorPages { dir: None }
+    }
+
+    /// Looks for pages in `dir`, named for their status code, like 404.html or 500.html. Statuses
+    /// without a page of their own still get plain text.
+    pub fn directory<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// The response for an error status.
+    pub fn render(&self, status: StatusCode) -> Response {
+        // A page that has gone missing, of all things, mustn't stop us from answering.
+
+        let page = self
+            .dir
+            .as_ref()
+            .and_then(|dir| fs::read(dir.join(format!("{}.html", status.as_u16()))).ok());
+        match page {
+            Some(page) => Response::new(status)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(page),
+            None => Response::text(status, &format!("{}\n", status)),
+        }
+    }
+}
+
+impl Middleware for ErrorPages {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let mut response = next.run(request);
+        if response.status.as_u16() < 400 || !response.body.is_empty() {
+            return response;
+        }
+
+        // Headers the handler set, like Allow or Retry-After, are kept.
+
+        let page = self.render(response.status);
+        for name in &["Content-Type", "Content-Length"] {
+            response.headers.remove(name);
+        }
+        for (name, value) in page.headers.iter() {
+            response.headers.insert(name, value);
+        }
+        response.body(page.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::temp_dir::TempDir;
+
+    fn request(target: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn body(response: Response) -> String {
+        match response.body {
+            crate::http::Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            other => panic!("unexpected body {:?}", other),
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(
+            ServerError::from(not_found).status_code(),
+            StatusCode::NotFound
+        );
+        let timeout = ReadError::HeaderTimeout;
+        assert_eq!(
+            ServerError::from(timeout).status_code(),
+            StatusCode::RequestTimeout
+        );
+        assert_eq!(
+            ServerError::internal("oops").status_code(),
+            StatusCode::InternalServerError
+        );
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(ServerError::from(reset).is_disconnect());
+        assert!(!ServerError::internal("oops").is_disconnect());
+    }
+
+    #[test]
+    fn fills_in_error_pages() {
+        let dir = TempDir::new("error-pages");
+        fs::write(dir.join("404.html"), "<h1>Lost?</h1>").unwrap();
+
+        let router = Router::new()
+            .get(
+                "/missing",
+                fallible(|_| Ok(Response::text(StatusCode::Ok, &fs::read_to_string("nope")?))),
+            )
+            .get(
+                "/broken",
+                fallible(|_| Err(ServerError::internal("no template"))),
+            )
+            .get("/own", |_| Response::text(StatusCode::NotFound, "mine"))
+            .wrap(ErrorPages::new().directory(&dir));
+
+        let response = router.handle(request("/missing"));
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(body(response), "<h1>Lost?</h1>");
+
+        let response = router.handle(request("/broken"));
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        assert_eq!(body(response), "500 Internal Server Error\n");
+
+        // Responses that come with a body keep it.
+        assert_eq!(body(router.handle(request("/own"))), "mine");
+    }
+}
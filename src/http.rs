@@ -0,0 +1,1815 @@
+// A small, dependency free implementation of the parts of HTTP/1.1 that our web server needs.
+//
+// HTTP is a text-based protocol, and a request takes this format:
+//
+// Method Request-URI HTTP-Version CRLF
+// headers CRLF
+// message-body
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The request method. Methods are case-sensitive, so `get` is an (unknown) extension method
+/// rather than GET.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(method) => method,
+        }
+    }
+
+    fn parse(token: &str) -> Result<Method, ParseError> {
+        if !is_token(token) {
+            return Err(ParseError::InvalidMethod);
+        }
+
+        Ok(match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Header fields, in the order they were received. Header names are case-insensitive, so every
+/// lookup ignores case, but the original spelling is kept for when we write the headers back out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers { fields: Vec::new() }
+    }
+
+    /// The value of the first field with this name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The values of every field with this name, such as several `Accept` lines.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a comma-separated field such as `Connection: keep-alive, Upgrade` lists `token`,
+    /// ignoring case.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a field, keeping any other fields with the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces every field with this name with a single one.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it was sent, e.g. `/posts?page=2`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Parameters captured from the path by the Router, e.g. `id` for `/posts/:id`.
+    pub params: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Parses the request line and headers at the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't contain the whole head of the request yet, in which case
+    /// the caller should read some more and try again. Otherwise returns the request along with
+    /// the number of bytes that made up its head; whatever follows is the start of the body (or
+    /// of the next request).
+    ///
+    /// The body is not parsed here, so the returned request always has an empty body.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        let mut lines = Lines { buf, pos: 0 };
+
+        // Reject a broken request line as soon as we have it, rather than waiting for headers that
+        // might never come.
+
+        let request_line = match lines.next()? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let (method, target, version) = parse_request_line(request_line)?;
+
+        let mut headers = Headers::new();
+        loop {
+            match lines.next()? {
+                None => return Ok(None),
+                Some("") => break,
+                Some(line) => {
+                    let (name, value) = parse_header(line)?;
+                    headers.append(name, value);
+                }
+            }
+        }
+
+        let request = Request {
+            method,
+            target: target.to_string(),
+            version,
+            headers,
+            body: Vec::new(),
+            params: Vec::new(),
+        };
+
+        Ok(Some((request, lines.pos)))
+    }
+
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(end) => &self.target[..end],
+            None => &self.target,
+        }
+    }
+
+    /// Whatever follows the `?` in the request target, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|start| &self.target[start + 1..])
+    }
+
+    /// Whether the client wants to keep the connection open for another request once this one
+    /// has been answered. HTTP/1.1 connections stay open unless the client says otherwise, while
+    /// HTTP/1.0 ones close unless the client asks for keep-alive.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.contains_token("Connection", "close") {
+            false
+        } else {
+            self.version == Version::Http11
+                || self.headers.contains_token("Connection", "keep-alive")
+        }
+    }
+
+    /// A parameter captured by the route that matched this request. Parameters are taken from the
+    /// path as-is, so they are still percent-encoded.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Decodes `%XX` escapes, such as in a path or a query string. Returns `None` if an escape is
+/// malformed. The result is bytes rather than a string, because nothing stops a client from
+/// encoding bytes that aren't valid UTF-8.
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix would also accept a sign, as in `%+1`, so check the digits ourselves.
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Escapes everything but letters, digits and `-._~` as `%XX`, so a name can be put in a path
+/// or a query string as it is.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Why a request couldn't be parsed. The server answers all of these with 400 Bad Request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The request line isn't `METHOD TARGET VERSION`.
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidTarget,
+    /// Anything other than HTTP/1.0 or HTTP/1.1.
+    UnsupportedVersion,
+    /// A header line without a colon, with an invalid name, or using the obsolete line folding.
+    InvalidHeader,
+    /// The head of the request isn't valid UTF-8 (or rather, isn't the ASCII it should be).
+    InvalidEncoding,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ParseError::InvalidRequestLine => "malformed request line",
+            ParseError::InvalidMethod => "invalid request method",
+            ParseError::InvalidTarget => "invalid request target",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::InvalidHeader => "malformed header field",
+            ParseError::InvalidEncoding => "request head is not valid UTF-8",
+        };
+        f.write_str(message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// How much of a request we are willing to buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The request line and headers together. Larger requests get 431.
+    pub max_head: usize,
+    /// The body, after undoing any chunked encoding. Larger requests get 413.
+    pub max_body: usize,
+    /// How long to wait for the first byte of a request, or None to wait forever.
+    pub idle_timeout: Option<Duration>,
+    /// How long the client gets to send the request line and headers, counting from their first
+    /// byte. Taking any longer gets 408.
+    pub header_timeout: Option<Duration>,
+    /// How long the client gets to send the body, counting from the end of the headers. Taking
+    /// any longer gets 408.
+    pub body_timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_head: 8 * 1024,
+            max_body: 1024 * 1024,
+            idle_timeout: None,
+            header_timeout: None,
+            body_timeout: None,
+        }
+    }
+}
+
+/// A stream whose reads can be told to give up after a while. RequestReader needs this to enforce
+/// the timeouts in its Limits.
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<T: ReadTimeout + ?Sized> ReadTimeout for &T {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+/// Why a whole request couldn't be read.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Parse(ParseError),
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// A Content-Length that isn't a number, a broken chunk, or framing we can't trust, such as
+    /// both a Content-Length and a Transfer-Encoding.
+    InvalidBody,
+    /// A Transfer-Encoding other than chunked.
+    UnsupportedTransferEncoding,
+    /// The connection was closed half way through a request.
+    UnexpectedEof,
+    /// The client didn't start another request within the idle timeout. There's no request to
+    /// answer, so the connection is closed without a response.
+    IdleTimeout,
+    /// The client took longer than the header timeout to send the request line and headers.
+    HeaderTimeout,
+    /// The client took longer than the body timeout to send the body.
+    BodyTimeout,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(err) => write!(f, "unable to read request: {}", err),
+            ReadError::Parse(err) => err.fmt(f),
+            ReadError::HeadersTooLarge => f.write_str("request headers are too large"),
+            ReadError::BodyTooLarge => f.write_str("request body is too large"),
+            ReadError::InvalidBody => f.write_str("malformed request body"),
+            ReadError::UnsupportedTransferEncoding => f.write_str("unsupported transfer encoding"),
+            ReadError::UnexpectedEof => f.write_str("connection closed in the middle of a request"),
+            ReadError::IdleTimeout => f.write_str("connection was idle for too long"),
+            ReadError::HeaderTimeout => f.write_str("request headers took too long to arrive"),
+            ReadError::BodyTimeout => f.write_str("request body took too long to arrive"),
+        }
+    }
+}
+
+impl ReadError {
+    /// The status to answer the request with. Io and UnexpectedEof errors mean the client is
+    /// probably gone, but if anyone is still listening, it's the client's fault. An idle timeout
+    /// is never answered, since there's no request, so it has no status of its own.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ReadError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ReadError::BodyTooLarge => StatusCode::PayloadTooLarge,
+            ReadError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+            ReadError::HeaderTimeout | ReadError::BodyTimeout => StatusCode::RequestTimeout,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReadError::Io(err) => Some(err),
+            ReadError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        ReadError::Io(err)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(err: ParseError) -> Self {
+        ReadError::Parse(err)
+    }
+}
+
+/// Reads whole requests, bodies included, from a stream.
+///
+/// Bytes are read in blocks, so a read can return more than one request's worth of data. Anything
+/// past the end of the current request is kept in the reader's buffer, ready for the next call to
+/// `read_request`.
+///
+/// The reader enforces the timeouts in its Limits by setting the stream's read timeout before each
+/// read, replacing whatever timeout the stream had.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+    phase: Phase,
+    deadline: Option<Instant>,
+}
+
+// Which part of a request we're waiting for, and so which timeout applies.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Head,
+    Body,
+}
+
+impl<R> RequestReader<R> {
+    pub fn new(inner: R, limits: Limits) -> Self {
+        RequestReader {
+            inner,
+            buf: Vec::new(),
+            limits,
+            phase: Phase::Idle,
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read + ReadTimeout> RequestReader<R> {
+    /// Reads the next request. Returns `Ok(None)` if the stream ends cleanly before the request
+    /// starts, which is how a client tells us it has nothing more to say.
+    pub fn read_request(&mut self) -> Result<Option<Request>, ReadError> {
+        // If part of this request arrived along with the last one, it has already started.
+        self.begin(if self.buf.is_empty() {
+            Phase::Idle
+        } else {
+            Phase::Head
+        });
+
+        let mut request = loop {
+            if let Some((request, head)) = Request::parse(&self.buf)? {
+                if head > self.limits.max_head {
+                    return Err(ReadError::HeadersTooLarge);
+                }
+                self.buf.drain(..head);
+                break request;
+            }
+
+            if self.buf.len() > self.limits.max_head {
+                return Err(ReadError::HeadersTooLarge);
+            }
+            if self.fill()? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ReadError::UnexpectedEof);
+            }
+            if self.phase == Phase::Idle {
+                self.begin(Phase::Head);
+            }
+        };
+
+        self.begin(Phase::Body);
+
+        // A message body is framed either by a Content-Length, or by chunked transfer coding;
+        // a request with neither doesn't have a body. A request with both is one that two
+        // servers in a chain may well frame differently, which is how one request gets smuggled
+        // inside another, so we refuse it outright. Erroring also closes the connection, which
+        // the spec insists on for such a request.
+
+        if request.headers.contains("Transfer-Encoding") {
+            if request.headers.contains("Content-Length") {
+                return Err(ReadError::InvalidBody);
+            }
+
+            // Transfer codings are listed in the order they were applied, across however many
+            // fields, and chunked has to come last, or we'd have no way of telling where the body
+            // ends. Chunked is also the only coding we know how to undo.
+
+            let codings: Vec<&str> = request
+                .headers
+                .get_all("Transfer-Encoding")
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .collect();
+            let (last, rest) = codings.split_last().unwrap_or((&"", &[]));
+            if rest
+                .iter()
+                .any(|coding| coding.eq_ignore_ascii_case("chunked"))
+            {
+                return Err(ReadError::InvalidBody);
+            }
+            if !rest.is_empty() || !last.eq_ignore_ascii_case("chunked") {
+                return Err(ReadError::UnsupportedTransferEncoding);
+            }
+            request.body = self.read_chunked()?;
+        } else if request.headers.contains("Content-Length") {
+            let length = content_length(&request.headers)?;
+            if length > self.limits.max_body {
+                return Err(ReadError::BodyTooLarge);
+            }
+            request.body = self.take(length)?;
+        }
+
+        Ok(Some(request))
+    }
+
+    // Starts the clock on the next part of the request.
+
+    fn begin(&mut self, phase: Phase) {
+        let timeout = match phase {
+            Phase::Idle => self.limits.idle_timeout,
+            Phase::Head => self.limits.header_timeout,
+            Phase::Body => self.limits.body_timeout,
+        };
+        self.phase = phase;
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    fn timed_out(&self) -> ReadError {
+        match self.phase {
+            Phase::Idle => ReadError::IdleTimeout,
+            Phase::Head => ReadError::HeaderTimeout,
+            Phase::Body => ReadError::BodyTimeout,
+        }
+    }
+
+    // Reads another block from the stream into the buffer, returning how many bytes were read.
+    //
+    // A timeout on each read wouldn't be enough on its own: a client can keep a connection busy
+    // forever by sending a byte just before every read times out (a "slowloris" attack). So each
+    // read only gets whatever time is left until the deadline for this part of the request.
+
+    fn fill(&mut self) -> Result<usize, ReadError> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    return Err(self.timed_out());
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        self.inner.set_read_timeout(timeout)?;
+
+        let mut block = [0; 4096];
+        let bytes_read = match self.inner.read(&mut block) {
+            Ok(bytes_read) => bytes_read,
+            // Depending on the platform, a read that times out fails with either of these.
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(self.timed_out())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.buf.extend_from_slice(&block[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn take(&mut self, length: usize) -> Result<Vec<u8>, ReadError> {
+        while self.buf.len() < length {
+            if self.fill()? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+        Ok(self.buf.drain(..length).collect())
+    }
+
+    // Takes a line, without its line ending, refusing to buffer more than max_head bytes for it.
+
+    fn take_line(&mut self) -> Result<Vec<u8>, ReadError> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buf.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+
+            if self.buf.len() > self.limits.max_head {
+                return Err(ReadError::InvalidBody);
+            }
+            if self.fill()? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+    }
+
+    // A chunked body is a series of chunks, each one being its size in hex on a line of its own,
+    // followed by that many bytes and a line ending. A chunk of size zero ends the body, and is
+    // followed by optional trailer fields and a blank line:
+    //
+    // 5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n
+
+    fn read_chunked(&mut self) -> Result<Vec<u8>, ReadError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = self.take_line()?;
+            let line = std::str::from_utf8(&line).map_err(|_| ReadError::InvalidBody)?;
+
+            // Chunk extensions (";name=value") are allowed after the size, and can be ignored.
+            // from_str_radix would also accept a sign, as in `+5`, so check the digits ourselves.
+            let size = line.split(';').next().unwrap_or("");
+            if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ReadError::InvalidBody);
+            }
+            let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::InvalidBody)?;
+
+            if size == 0 {
+                break;
+            }
+            if size > self.limits.max_body - body.len() {
+                return Err(ReadError::BodyTooLarge);
+            }
+
+            body.extend(self.take(size)?);
+            if !self.take_line()?.is_empty() {
+                return Err(ReadError::InvalidBody);
+            }
+        }
+
+        // We have no use for trailer fields, but they still need to be consumed. They're as much
+        // a part of the head as any other field, so they get the same limit, or a client could
+        // keep us reading them forever.
+
+        let mut trailers = 0;
+        loop {
+            let line = self.take_line()?;
+            if line.is_empty() {
+                break;
+            }
+            trailers += line.len() + 2;
+            if trailers > self.limits.max_head {
+                return Err(ReadError::HeadersTooLarge);
+            }
+        }
+
+        Ok(body)
+    }
+}
+
+fn content_length(headers: &Headers) -> Result<usize, ReadError> {
+    // Several Content-Length fields are only acceptable if they all agree, as a disagreement is a
+    // classic way of smuggling one request inside another.
+
+    let mut lengths = headers.get_all("Content-Length");
+    let first = lengths.next().unwrap_or("");
+    if lengths.any(|other| other != first) || !first.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ReadError::InvalidBody);
+    }
+    first.parse().map_err(|_| ReadError::InvalidBody)
+}
+
+// Responses have the following format:
+//
+// HTTP-Version Status-Code Reason-Phrase CRLF
+// headers CRLF
+// message-body
+
+macro_rules! status_codes {
+    ($($name:ident = $code:expr, $reason:expr;)*) => {
+        /// The standard status codes, along with the reason phrase we send for each of them.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum StatusCode {
+            $($name,)*
+        }
+
+        impl StatusCode {
+            pub fn as_u16(&self) -> u16 {
+                match self {
+                    $(StatusCode::$name => $code,)*
+                }
+            }
+
+            pub fn reason_phrase(&self) -> &'static str {
+                match self {
+                    $(StatusCode::$name => $reason,)*
+                }
+            }
+
+            pub fn from_u16(code: u16) -> Option<StatusCode> {
+                match code {
+                    $($code => Some(StatusCode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    PayloadTooLarge = 413, "Payload Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+}
+
+impl StatusCode {
+    /// Responses with these codes never have a body, so they don't get a Content-Length either.
+    pub fn allows_body(&self) -> bool {
+        let code = self.as_u16();
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+/// The body of a response.
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Sent straight from disk a few kilobytes at a time, so the file never has to fit in memory.
+    File(File),
+    /// Anything we can read from, along with how many bytes to send from it.
+    Sized(Box<dyn Read + Send>, u64),
+    /// Anything else we can read from. We don't know how long a stream is, so it's sent in
+    /// chunks, each of which says how long it is.
+    Stream(Box<dyn Read + Send>),
+    /// Written by a function while the response is being sent, for bodies that are made as they
+    /// go. Like a stream, it's sent in chunks.
+    Writer(WriteBody),
+}
+
+/// A function that writes a body.
+pub type WriteBody = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+impl Body {
+    /// A body that's read from `reader` as it's sent.
+    pub fn stream<R: Read + Send + 'static>(reader: R) -> Self {
+        Body::Stream(Box::new(reader))
+    }
+
+    /// A body that `write` writes as it's sent, like a report written a row at a time with
+    /// `writeln!(out, ...)`. What it writes is buffered, and sent whenever the buffer fills up.
+    /// Flushing sends whatever has been written so far straight away.
+    pub fn writer<F>(write: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        Body::Writer(Box::new(write))
+    }
+
+    /// A body that's sent a chunk at a time, each one as soon as the iterator comes up with it.
+    pub fn chunks<I>(chunks: I) -> Self
+    where
+        I: IntoIterator + Send + 'static,
+        I::Item: AsRef<[u8]>,
+    {
+        Body::writer(move |out| {
+            for chunk in chunks {
+                out.write_all(chunk.as_ref())?;
+                out.flush()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The length of the body, if we know it without reading it.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(file) => file.metadata().ok().map(|metadata| metadata.len()),
+            Body::Sized(_, length) => Some(*length),
+            Body::Stream(_) | Body::Writer(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // A best guess for when a response doesn't say what it contains.
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Body::Bytes(bytes) => {
+                let start = &bytes[..bytes.len().min(64)];
+                let start = String::from_utf8_lossy(start).to_ascii_lowercase();
+                if start.trim_start().starts_with("<!doctype html") || start.contains("<html") {
+                    "text/html; charset=utf-8"
+                } else if std::str::from_utf8(bytes).is_ok() {
+                    "text/plain; charset=utf-8"
+                } else {
+                    "application/octet-stream"
+                }
+            }
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File(file) => write!(f, "File({:?})", file),
+            Body::Sized(_, length) => write!(f, "Sized({} bytes)", length),
+            Body::Stream(_) => f.write_str("Stream"),
+            Body::Writer(_) => f.write_str("Writer"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Body::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Self {
+        Body::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<File> for Body {
+    fn from(file: File) -> Self {
+        Body::File(file)
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    /// An empty response with this status.
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Empty,
+        }
+    }
+
+    /// A response with a short plain text body, such as for an error.
+    pub fn text(status: StatusCode, text: &str) -> Self {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(text)
+    }
+
+    /// Sets a header, replacing any previous value.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body<B: Into<Body>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Whether the handler asked for the connection to be closed once this response has been
+    /// sent.
+    pub fn closes_connection(&self) -> bool {
+        self.headers.contains_token("Connection", "close")
+    }
+
+    /// Whether the body goes out in chunks, because its length isn't known up front.
+    pub fn is_chunked(&self) -> bool {
+        self.status.allows_body() && self.body.len().is_none()
+    }
+
+    /// Writes the status line, the headers and the body, and returns how many bytes of body were
+    /// written.
+    ///
+    /// Date, Content-Length and Content-Type are added unless the response already has them.
+    /// Headers that are set explicitly always win, so a handler can e.g. send a Content-Type the
+    /// body sniffing would have got wrong.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<u64> {
+        self.write(writer, true)
+    }
+
+    /// Writes the status line and headers without the body, as the answer to a HEAD request.
+    /// The headers are the ones `write_to` would have sent, Content-Length included, so the
+    /// client learns everything about the body except what's in it.
+    pub fn write_head_to<W: Write>(self, writer: &mut W) -> io::Result<u64> {
+        self.write(writer, false)
+    }
+
+    fn write<W: Write>(mut self, writer: &mut W, send_body: bool) -> io::Result<u64> {
+        if !self.headers.contains("Date") {
+            self.headers.insert("Date", &http_date(SystemTime::now()));
+        }
+
+        if self.status.allows_body() {
+            match self.body.len() {
+                Some(length) if !self.headers.contains("Content-Length") => {
+                    self.headers.insert("Content-Length", &length.to_string())
+                }
+                // Without a length, the body is sent in chunks that each start with their own
+                // length, and an empty one marks the end. If the connection is closing anyway,
+                // closing it does the same job.
+                None if !self.closes_connection() => {
+                    self.headers.remove("Content-Length");
+                    self.headers.insert("Transfer-Encoding", "chunked")
+                }
+                _ => {}
+            }
+            if !self.headers.contains("Content-Type") && !self.body.is_empty() {
+                self.headers
+                    .insert("Content-Type", self.body.content_type());
+            }
+        }
+
+        // Buffering the head means it goes out in a single write, rather than one per header.
+
+        let mut head = format!("{} {}\r\n", Version::Http11, self.status);
+        for (name, value) in self.headers.iter() {
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        if !send_body || !self.status.allows_body() {
+            return Ok(0);
+        }
+        let chunked = self.headers.contains_token("Transfer-Encoding", "chunked");
+        match self.body {
+            Body::Empty => Ok(0),
+            Body::Bytes(bytes) => {
+                writer.write_all(&bytes)?;
+                Ok(bytes.len() as u64)
+            }
+            Body::File(mut file) => io::copy(&mut file, writer),
+            Body::Sized(reader, length) => io::copy(&mut reader.take(length), writer),
+            Body::Stream(mut stream) => {
+                let mut body = BodyWriter::new(writer, chunked);
+                io::copy(&mut stream, &mut body)?;
+                body.finish()
+            }
+            Body::Writer(write) => {
+                let mut body =
+                    BufWriter::with_capacity(CHUNK_SIZE, BodyWriter::new(writer, chunked));
+                write(&mut body)?;
+                body.into_inner().map_err(|err| err.into_error())?.finish()
+            }
+        }
+    }
+}
+
+// How much a writer body buffers before sending it as a chunk.
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+// Writes a body of unknown length, wrapping each write in a chunk if the body is chunked, in the
+// same format `read_chunked` reads.
+
+struct BodyWriter<'a, W: Write> {
+    writer: &'a mut W,
+    chunked: bool,
+    written: u64,
+}
+
+impl<'a, W: Write> BodyWriter<'a, W> {
+    fn new(writer: &'a mut W, chunked: bool) -> Self {
+        BodyWriter {
+            writer,
+            chunked,
+            written: 0,
+        }
+    }
+
+    // Ends the body, and returns how many bytes of it were written, not counting the framing.
+
+    fn finish(self) -> io::Result<u64> {
+        if self.chunked {
+            self.writer.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(self.written)
+    }
+}
+
+impl<'a, W: Write> Write for BodyWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body early.
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // The chunk goes out in a single write, so it doesn't get split over several packets.
+
+        if self.chunked {
+            let mut chunk = format!("{:x}\r\n", buf.len()).into_bytes();
+            chunk.extend_from_slice(buf);
+            chunk.extend_from_slice(b"\r\n");
+            self.writer.write_all(&chunk)?;
+        } else {
+            self.writer.write_all(buf)?;
+        }
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Formats a time the way HTTP wants it in headers like Date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = seconds / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    // The epoch was a Thursday.
+    let weekday = DAYS[((days + 4) % 7) as usize];
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Formats a time as ISO 8601, e.g. `2000-10-10T13:55:36Z`.
+pub(crate) fn iso_8601(time: SystemTime) -> String {
+    let (year, month, day, hours, minutes, seconds) = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hours, minutes, seconds
+    )
+}
+
+// Breaks a time down into (year, month, day, hours, minutes, seconds), in UTC.
+
+pub(crate) fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    (
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+/// Parses a date from a header like If-Modified-Since. Besides the format `http_date` writes, the
+/// spec says we have to understand two older ones:
+///
+/// Sun, 06 Nov 1994 08:49:37 GMT    (IMF-fixdate, what everyone sends today)
+/// Sunday, 06-Nov-94 08:49:37 GMT   (RFC 850)
+/// Sun Nov  6 08:49:37 1994         (C's asctime)
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // All three have the same pieces, just in a different order, so we pick them out by what
+    // they look like. The day always comes before the year.
+
+    let mut month = None;
+    let mut time = None;
+    let mut numbers = Vec::new();
+    for token in s.split([' ', ',', '-']) {
+        if let Some(index) = MONTHS.iter().position(|&m| m == token) {
+            month = Some(index as u32 + 1);
+        } else if token.contains(':') {
+            time = Some(token);
+        } else if !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()) {
+            numbers.push(token);
+        }
+    }
+
+    let (day, year) = match numbers[..] {
+        [day, year] => (day.parse::<u32>().ok()?, year),
+        _ => return None,
+    };
+    let year = match (year.len(), year.parse::<i64>().ok()?) {
+        // RFC 850 dates only have two digits. The spec says to take the century that puts the
+        // date nearest to now, which for the dates we'll see means these.
+        (2, year) if year < 70 => 2000 + year,
+        (2, year) => 1900 + year,
+        (4, year) => year,
+        _ => return None,
+    };
+
+    let mut clock = time?.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let month = month?;
+    if day == 0 || day > 31 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    let since = days as u64 * 86400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(since))
+}
+
+// Converts days since 1970-01-01 into a (year, month, day) date, using Howard Hinnant's algorithm
+// from http://howardhinnant.github.io/date_algorithms.html. It works in 400 year "eras", which
+// start on the 1st of March so that leap days fall at the end of the year.
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// The inverse of civil_from_days, turning a date back into days since 1970-01-01.
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Splits the head of a request into lines. Lines should end in CRLF, but like most servers we also
+// accept a bare LF, which makes it possible to talk to the server by hand with netcat.
+
+struct Lines<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn next(&mut self) -> Result<Option<&'a str>, ParseError> {
+        let rest = &self.buf[self.pos..];
+        let end = match rest.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+
+        self.pos += end + 1;
+        let line = rest[..end].strip_suffix(b"\r").unwrap_or(&rest[..end]);
+        std::str::from_utf8(line)
+            .map(Some)
+            .map_err(|_| ParseError::InvalidEncoding)
+    }
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), ParseError> {
+    let mut parts = line.split(' ');
+
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    let method = Method::parse(method)?;
+
+    // We only deal in origin-form (`/path?query`), absolute-form (`http://host/path`) and the
+    // asterisk-form that OPTIONS can use.
+
+    let valid_form = target.starts_with('/') || target == "*" || target.contains("://");
+    if !valid_form || target.bytes().any(|b| b.is_ascii_control() || b == b' ') {
+        return Err(ParseError::InvalidTarget);
+    }
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return Err(ParseError::UnsupportedVersion),
+    };
+
+    Ok((method, target, version))
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    // A line starting with whitespace continues the previous header (obs-fold). That's been
+    // deprecated for years, and the spec lets us reject it.
+
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    let colon = line.find(':').ok_or(ParseError::InvalidHeader)?;
+    let name = &line[..colon];
+    if !is_token(name) {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    Ok((name, value))
+}
+
+// A token is what the spec allows for method and header names: visible ASCII, minus delimiters.
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_browser_request() {
+        let raw = b"GET /?x=1 HTTP/1.1\r\n\
+                    Host: localhost:7878\r\n\
+                    user-agent: curl/7.64.1\r\n\
+                    Accept: */*\r\n\
+                    \r\n";
+
+        let (request, consumed) = Request::parse(raw).unwrap().unwrap();
+
+        assert_eq!(consumed, raw.len());
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/?x=1");
+        assert_eq!(request.path(), "/");
+        assert_eq!(request.query(), Some("x=1"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("User-Agent"), Some("curl/7.64.1"));
+        assert_eq!(request.headers.get("HOST"), Some("localhost:7878"));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_partial_request() {
+        let raw = b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\nleftover";
+
+        for end in 0..raw.len() - "\r\nleftover".len() {
+            assert_eq!(Request::parse(&raw[..end]), Ok(None), "at {}", end);
+        }
+
+        let (request, consumed) = Request::parse(raw).unwrap().unwrap();
+        assert_eq!(request.path(), "/sleep");
+        assert_eq!(&raw[consumed..], b"leftover");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: &[(&[u8], ParseError)] = &[
+            (b"GET /\r\n", ParseError::InvalidRequestLine),
+            (b"GET  / HTTP/1.1\r\n", ParseError::InvalidRequestLine),
+            (b"G(T / HTTP/1.1\r\n", ParseError::InvalidMethod),
+            (b"GET index.html HTTP/1.1\r\n", ParseError::InvalidTarget),
+            (b"GET / HTTP/2.0\r\n", ParseError::UnsupportedVersion),
+            (
+                b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nA: b\r\n  folded\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(Request::parse(raw), Err(*expected));
+        }
+    }
+
+    fn read_all(raw: &[u8], limits: Limits) -> Result<Vec<Request>, ReadError> {
+        // Hand out the bytes a few at a time, the way a slow network might.
+        let mut reader = RequestReader::new(Trickle(raw), limits);
+        let mut requests = Vec::new();
+        while let Some(request) = reader.read_request()? {
+            requests.push(request);
+        }
+        Ok(requests)
+    }
+
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    impl ReadTimeout for Trickle<'_> {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_bodies_of_back_to_back_requests() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                    POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n\
+                    GET /c HTTP/1.1\r\n\r\n";
+
+        let requests = read_all(raw, Limits::default()).unwrap();
+
+        let bodies: Vec<&[u8]> = requests.iter().map(|r| r.body.as_slice()).collect();
+        assert_eq!(bodies, vec![&b"hello"[..], b"hello world", b""]);
+        assert_eq!(requests[2].path(), "/c");
+    }
+
+    #[test]
+    fn times_out_slow_clients() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_millis(50)),
+            header_timeout: Some(Duration::from_millis(200)),
+            body_timeout: Some(Duration::from_millis(200)),
+            ..Limits::default()
+        };
+
+        // Connects a client that sends `parts` with a pause between each of them, and returns what
+        // reading a request from it comes to.
+        let read = |parts: &'static [&'static [u8]], pause: Duration| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        thread::sleep(pause);
+                    }
+                    if stream.write_all(part).is_err() {
+                        break;
+                    }
+                }
+                // Hang around until the reader gives up on us.
+                let _ = stream.read(&mut [0; 1]);
+            });
+
+            let (stream, _) = listener.accept().unwrap();
+            let result = RequestReader::new(&stream, limits).read_request();
+            drop(stream);
+            client.join().unwrap();
+            result
+        };
+
+        let idle = read(&[], Duration::from_millis(0));
+        assert!(matches!(idle, Err(ReadError::IdleTimeout)), "{:?}", idle);
+
+        // Every byte arrives well within the idle timeout, but the headers as a whole take too
+        // long.
+        let trickle: &[&[u8]] = &[b"G", b"E", b"T", b" ", b"/", b" ", b"H", b"T", b"T", b"P"];
+        let slow_head = read(trickle, Duration::from_millis(40));
+        assert!(
+            matches!(slow_head, Err(ReadError::HeaderTimeout)),
+            "{:?}",
+            slow_head
+        );
+        assert_eq!(
+            slow_head.unwrap_err().status_code(),
+            StatusCode::RequestTimeout
+        );
+
+        let slow_body = read(
+            &[b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab", b"cd"],
+            Duration::from_millis(300),
+        );
+        assert!(
+            matches!(slow_body, Err(ReadError::BodyTimeout)),
+            "{:?}",
+            slow_body
+        );
+
+        let quick = read(&[b"GET / HTTP/1.1\r\n", b"\r\n"], Duration::from_millis(10));
+        assert_eq!(quick.unwrap().unwrap().path(), "/");
+    }
+
+    #[test]
+    fn works_out_whether_to_keep_the_connection_open() {
+        let cases: &[(&[u8], bool)] = &[
+            (b"GET / HTTP/1.1\r\n\r\n", true),
+            (b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+            (
+                b"GET / HTTP/1.1\r\nConnection: upgrade, close\r\n\r\n",
+                false,
+            ),
+        ];
+
+        for (raw, keep_alive) in cases {
+            let (request, _) = Request::parse(raw).unwrap().unwrap();
+            assert_eq!(request.keep_alive(), *keep_alive, "{:?}", request.headers);
+        }
+
+        // A body of unknown length is sent in chunks rather than closing the connection.
+        let stream = Body::stream(&b"unknown length"[..]);
+        let response = Response::new(StatusCode::Ok).body(stream);
+        assert!(response.is_chunked());
+        assert!(!response.closes_connection());
+        assert!(!Response::text(StatusCode::Ok, "hi").is_chunked());
+        assert!(Response::text(StatusCode::Ok, "bye")
+            .header("Connection", "close")
+            .closes_connection());
+    }
+
+    #[test]
+    fn reads_requests_larger_than_a_block() {
+        let mut raw = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        raw.extend(vec![b'a'; 5000]);
+        raw.extend(b"\r\n\r\n");
+
+        let requests = read_all(&raw, Limits::default()).unwrap();
+        assert_eq!(requests[0].headers.get("cookie").unwrap().len(), 5000);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_head: 64,
+            max_body: 8,
+            ..Limits::default()
+        };
+        let mut long_head = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        long_head.extend(vec![b'a'; 100]);
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5\r\n12345\r\n5\r\n12345\r\n0\r\n\r\n";
+
+        assert!(matches!(
+            read_all(&long_head, limits),
+            Err(ReadError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            read_all(
+                b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789",
+                limits
+            ),
+            Err(ReadError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            read_all(chunked, limits),
+            Err(ReadError::BodyTooLarge)
+        ));
+
+        // Lots of short trailers add up, even though none of them is too long on its own.
+        let mut trailers = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        for _ in 0..20 {
+            trailers.extend(b"X-Trailer: 1\r\n");
+        }
+        trailers.extend(b"\r\n");
+        assert!(matches!(
+            read_all(&trailers, limits),
+            Err(ReadError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_badly_framed_bodies() {
+        let cases: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n12",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n 3\r\nabc\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n\
+              0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+              Transfer-Encoding: identity\r\n\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+        ];
+        for raw in cases {
+            assert!(matches!(
+                read_all(raw, Limits::default()),
+                Err(ReadError::InvalidBody)
+            ));
+        }
+
+        // Chunked on its own is the only coding we can undo.
+        let unsupported: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+        ];
+        for raw in unsupported {
+            assert!(matches!(
+                read_all(raw, Limits::default()),
+                Err(ReadError::UnsupportedTransferEncoding)
+            ));
+        }
+
+        assert!(matches!(
+            read_all(
+                b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+                Limits::default()
+            ),
+            Err(ReadError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn writes_a_response_with_automatic_headers() {
+        let mut out = Vec::new();
+        Response::new(StatusCode::NotFound)
+            .header("Server", "rust_lang_book")
+            .body("<!DOCTYPE html><h1>Oops!</h1>")
+            .write_to(&mut out)
+            .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_at(out.find("\r\n\r\n").unwrap() + 4);
+
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\nServer: rust_lang_book\r\n"));
+        assert!(head.contains("\r\nDate: "));
+        assert!(head.contains("\r\nContent-Length: 29\r\n"));
+        assert!(head.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert_eq!(body, "<!DOCTYPE html><h1>Oops!</h1>");
+    }
+
+    #[test]
+    fn explicit_headers_win() {
+        let mut out = Vec::new();
+        Response::new(StatusCode::Ok)
+            .header("Content-Type", "application/json")
+            .body("{}")
+            .write_to(&mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("Content-Type: application/json\r\n"));
+        assert_eq!(out.matches("Content-Type").count(), 1);
+    }
+
+    #[test]
+    fn head_responses_leave_off_the_body() {
+        let mut out = Vec::new();
+        let written = Response::text(StatusCode::Ok, "hello")
+            .write_head_to(&mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(written, 0);
+        assert!(out.contains("\r\nContent-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_bodies_in_chunks() {
+        let write = |response: Response| {
+            let mut out = Vec::new();
+            let written = response.write_to(&mut out).unwrap();
+            (String::from_utf8(out).unwrap(), written)
+        };
+
+        let (out, written) =
+            write(Response::new(StatusCode::Ok).body(Body::chunks(vec!["Hello", "", ", world"])));
+        assert!(out.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n"));
+        assert_eq!(written, 12);
+
+        // Writes are buffered into bigger chunks until the body is flushed.
+        let (out, _) = write(Response::new(StatusCode::Ok).body(Body::writer(|out| {
+            out.write_all(b"ab")?;
+            out.write_all(b"cd")?;
+            out.flush()?;
+            out.write_all(b"e")
+        })));
+        assert!(out.ends_with("\r\n\r\n4\r\nabcd\r\n1\r\ne\r\n0\r\n\r\n"));
+
+        // If the connection is closing anyway, there's no need for chunks.
+        let (out, _) = write(
+            Response::new(StatusCode::Ok)
+                .header("Connection", "close")
+                .body(Body::stream(&b"raw"[..])),
+        );
+        assert!(!out.contains("Transfer-Encoding"));
+        assert!(out.ends_with("\r\n\r\nraw"));
+    }
+
+    #[test]
+    fn no_content_has_no_body_headers() {
+        let mut out = Vec::new();
+        Response::new(StatusCode::NoContent)
+            .body("ignored")
+            .write_to(&mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn status_codes_round_trip() {
+        assert_eq!(
+            StatusCode::from_u16(431),
+            Some(StatusCode::RequestHeaderFieldsTooLarge)
+        );
+        assert_eq!(StatusCode::ServiceUnavailable.as_u16(), 503);
+        assert_eq!(StatusCode::from_u16(299), None);
+        assert_eq!(StatusCode::Ok.to_string(), "200 OK");
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        let leap_day = UNIX_EPOCH + std::time::Duration::from_secs(951_782_400);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+
+        for s in &[
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(s), Some(time), "{}", s);
+        }
+
+        let leap_day = UNIX_EPOCH + std::time::Duration::from_secs(951_782_400);
+        assert_eq!(parse_http_date(&http_date(leap_day)), Some(leap_day));
+
+        for s in &[
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 25:49:37 GMT",
+            "Sun, 06 Nov 1994",
+        ] {
+            assert_eq!(parse_http_date(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "*/*");
+        headers.insert("Content-Length", "1");
+        headers.insert("content-length", "2");
+
+        assert_eq!(
+            headers.get_all("ACCEPT").collect::<Vec<_>>(),
+            vec!["text/html", "*/*"]
+        );
+        assert_eq!(headers.get("Content-Length"), Some("2"));
+        assert_eq!(headers.len(), 3);
+    }
+}
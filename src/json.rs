@@ -0,0 +1,36 @@
+// Just enough JSON to write it out. The thread pool's traces and the access log both build their
+// JSON by hand, and need their strings escaped the same way.
+
+use std::fmt::Write;
+
+/// Quotes a string for JSON, escaping anything that needs it.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("GET /\"a\"\n"), "\"GET /\\\"a\\\"\\n\"");
+        assert_eq!(json_string("bell\u{7}"), "\"bell\\u0007\"");
+    }
+}
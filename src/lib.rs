@@ -1,9 +1,25 @@
 // import modules here so that they'll run in our test suite
+pub mod access_log;
+pub mod advanced_traits;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod deflate;
 pub mod different_types_blog;
+pub mod error;
 pub mod fearless_concurrency;
-pub mod state_pattern_blog;
-pub mod advanced_traits;
+pub mod http;
+pub mod json;
+pub mod listing;
+pub mod middleware;
+pub mod range;
+pub mod router;
+pub mod server;
+#[cfg(unix)]
+pub mod signal;
 pub mod smart_pointers;
+pub mod state_pattern_blog;
+pub mod static_files;
+#[cfg(test)]
+mod temp_dir;
 pub mod thread_pool;
-
-
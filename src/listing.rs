@@ -0,0 +1,411 @@
+// Directory listings, for directories that don't have an index file of their own.
+//
+// The listing is a page of links to everything in the directory, with their sizes and when they
+// were last changed. It can be sorted by any of those with the query string:
+//
+// GET /static/downloads/?sort=size&order=desc
+//
+// and a client that asks for JSON gets the same thing as JSON:
+//
+// GET /static/downloads/
+// Accept: application/json
+//
+// {"path":"/static/downloads/","entries":[{"name":"notes.txt","type":"file","size":1024,...}]}
+
+use crate::error::ServerError;
+use crate::http::{
+    iso_8601, percent_decode, percent_encode, Headers, Request, Response, StatusCode,
+};
+use crate::json::json_string;
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Something in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// The size of a file. Directories don't have one.
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// What a listing is sorted by. Directories always come before files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// How a listing is sorted, from the `sort` and `order` query parameters. Anything we don't
+/// recognise falls back to sorting by name, A to Z.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Sort {
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut sort = Sort {
+            key: SortKey::Name,
+            descending: false,
+        };
+        for pair in query.unwrap_or("").split('&') {
+            match pair {
+                "sort=name" => sort.key = SortKey::Name,
+                "sort=size" => sort.key = SortKey::Size,
+                "sort=modified" => sort.key = SortKey::Modified,
+                "order=asc" => sort.descending = false,
+                "order=desc" => sort.descending = true,
+                _ => {}
+            }
+        }
+        sort
+    }
+
+    fn compare(&self, a: &Entry, b: &Entry) -> Ordering {
+        let ordering = match self.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+
+        let ordering = if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    }
+
+    // The query string for a column's heading. Clicking the column the listing is already sorted
+    // by flips the order.
+
+    fn link(&self, key: SortKey) -> String {
+        let order = if key == self.key && !self.descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!("?sort={}&amp;order={}", key.as_str(), order)
+    }
+}
+
+/// Reads the entries of a directory. Hidden files, whose names start with a dot, are left out,
+/// as are entries we can't read.
+pub fn read(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+
+        // fs::metadata follows symlinks, so a link to a directory is listed as a directory.
+
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() {
+                None
+            } else {
+                Some(metadata.len())
+            },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Redirects a request for a directory whose URL doesn't end with a slash to the one that does.
+///
+/// Relative links on a directory's page, be it a listing or an index file, are relative to the
+/// directory, so its URL has to end with a slash. Otherwise a link to `notes.txt` on /static/docs
+/// would lead to /static/notes.txt rather than /static/docs/notes.txt.
+pub fn add_slash(request: &Request) -> Option<Response> {
+    let path = request.path();
+    if path.ends_with('/') {
+        return None;
+    }
+
+    let location = match request.query() {
+        Some(query) => format!("{}/?{}", path, query),
+        None => format!("{}/", path),
+    };
+    Some(Response::new(StatusCode::MovedPermanently).header("Location", &location))
+}
+
+/// Answers a request for a directory with a listing of what's in it.
+pub fn response(request: &Request, dir: &Path) -> Response {
+    if let Some(redirect) = add_slash(request) {
+        return redirect;
+    }
+
+    let mut entries = match read(dir) {
+        Ok(entries) => entries,
+        Err(err) => return ServerError::from(err).into(),
+    };
+    let sort = Sort::from_query(request.query());
+    entries.sort_by(|a, b| sort.compare(a, b));
+
+    let path = request.path();
+    let title = percent_decode(path)
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_else(|| path.to_string());
+
+    // The listing changes whenever the directory does, so it shouldn't be cached like the files
+    // in it are.
+
+    let response = Response::new(StatusCode::Ok)
+        .header("Vary", "Accept")
+        .header("Cache-Control", "no-cache");
+    if wants_json(&request.headers) {
+        response
+            .header("Content-Type", "application/json")
+            .body(json(&title, &entries))
+    } else {
+        let parent = request
+            .param("path")
+            .is_some_and(|p| !p.is_empty() && p != "/");
+        response
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(html(&title, &entries, sort, parent))
+    }
+}
+
+// Browsers ask for HTML, often with `*/*` thrown in, so JSON has to be asked for by name and
+// preferred over HTML.
+
+fn wants_json(headers: &Headers) -> bool {
+    let (mut json, mut html) = (0.0, 0.0);
+    for item in headers.get_all("Accept").flat_map(|value| value.split(',')) {
+        let mut parts = item.split(';');
+        let media = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        match media.as_str() {
+            "application/json" => json = q,
+            "text/html" | "text/*" | "*/*" => html = f32::max(html, q),
+            _ => {}
+        }
+    }
+    json > 0.0 && json >= html
+}
+
+/// Writes a listing as an HTML page.
+pub fn html(title: &str, entries: &[Entry], sort: Sort, parent: bool) -> String {
+    let title = escape(title);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th><a href=\"{}\">Name</a></th><th><a href=\"{}\">Size</a></th>\
+         <th><a href=\"{}\">Modified</a></th></tr>\n",
+        sort.link(SortKey::Name),
+        sort.link(SortKey::Size),
+        sort.link(SortKey::Modified),
+        title = title
+    );
+
+    if parent {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let _ = writeln!(
+            page,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode(&entry.name),
+            slash,
+            escape(&entry.name),
+            slash,
+            entry.size.map_or("-".to_string(), human_size),
+            entry.modified.map_or(String::new(), |time| {
+                // 2020-08-02T21:44:28Z becomes 2020-08-02 21:44.
+                let iso = iso_8601(time);
+                format!("{} {}", &iso[..10], &iso[11..16])
+            })
+        );
+    }
+
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+/// Writes a listing as JSON.
+pub fn json(path: &str, entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let mut json = format!(
+                "{{\"name\":{},\"type\":\"{}\"",
+                json_string(&entry.name),
+                if entry.is_dir { "directory" } else { "file" }
+            );
+            if let Some(size) = entry.size {
+                let _ = write!(json, ",\"size\":{}", size);
+            }
+            if let Some(modified) = entry.modified {
+                let _ = write!(json, ",\"modified\":\"{}\"", iso_8601(modified));
+            }
+            json.push('}');
+            json
+        })
+        .collect();
+
+    format!(
+        "{{\"path\":{},\"entries\":[{}]}}",
+        json_string(path),
+        entries.join(",")
+    )
+}
+
+// Sizes as people read them, e.g. 1.5 KiB.
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// File names can contain anything, including `<` and `"`, so they're escaped before going into
+// the page.
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entries() -> Vec<Entry> {
+        let at = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        vec![
+            Entry {
+                name: "b.txt".to_string(),
+                is_dir: false,
+                size: Some(10),
+                modified: at(3),
+            },
+            Entry {
+                name: "docs".to_string(),
+                is_dir: true,
+                size: None,
+                modified: at(1),
+            },
+            Entry {
+                name: "a <1>.txt".to_string(),
+                is_dir: false,
+                size: Some(2048),
+                modified: at(2),
+            },
+        ]
+    }
+
+    fn sorted(query: &str) -> Vec<String> {
+        let sort = Sort::from_query(Some(query));
+        let mut entries = entries();
+        entries.sort_by(|a, b| sort.compare(a, b));
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn sorts_with_directories_first() {
+        assert_eq!(sorted(""), ["docs", "a <1>.txt", "b.txt"]);
+        assert_eq!(sorted("order=desc"), ["docs", "b.txt", "a <1>.txt"]);
+        assert_eq!(
+            sorted("sort=size&order=desc"),
+            ["docs", "a <1>.txt", "b.txt"]
+        );
+        assert_eq!(sorted("sort=modified"), ["docs", "a <1>.txt", "b.txt"]);
+        assert_eq!(sorted("sort=bogus&order=up"), sorted(""));
+    }
+
+    #[test]
+    fn writes_html() {
+        let sort = Sort::from_query(Some("sort=size"));
+        let page = html("/static/", &entries(), sort, false);
+
+        assert!(page.contains("<title>Index of /static/</title>"));
+        assert!(page.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(page.contains(
+            "<a href=\"a%20%3C1%3E.txt\">a &lt;1&gt;.txt</a></td><td>2.0 KiB</td>\
+             <td>1970-01-01 00:00</td>"
+        ));
+        assert!(page.contains("<a href=\"?sort=size&amp;order=desc\">Size</a>"));
+        assert!(page.contains("<a href=\"?sort=name&amp;order=asc\">Name</a>"));
+        assert!(!page.contains("../"));
+    }
+
+    #[test]
+    fn writes_json() {
+        assert_eq!(
+            json("/static/", &entries()[..2]),
+            "{\"path\":\"/static/\",\"entries\":[\
+             {\"name\":\"b.txt\",\"type\":\"file\",\"size\":10,\
+             \"modified\":\"1970-01-01T00:00:03Z\"},\
+             {\"name\":\"docs\",\"type\":\"directory\",\"modified\":\"1970-01-01T00:00:01Z\"}]}"
+        );
+    }
+
+    #[test]
+    fn negotiates_json() {
+        let accepts = |accept: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nAccept: {}\r\n\r\n", accept);
+            let request = Request::parse(raw.as_bytes()).unwrap().unwrap().0;
+            wants_json(&request.headers)
+        };
+
+        assert!(accepts("application/json"));
+        assert!(accepts("application/json, text/plain, */*"));
+        assert!(!accepts("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(!accepts("*/*"));
+        assert!(!accepts("text/html, application/json;q=0.9"));
+    }
+}
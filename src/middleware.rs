@@ -0,0 +1,148 @@
+// Behaviour that wraps around every handler, such as logging, authentication or compression.
+//
+// A middleware gets the request before the handler does, along with `next`, which runs the rest of
+// the chain: the middlewares after it, and finally the handler. It can change the request before
+// passing it on, change the response on the way back, or not call `next` at all and answer the
+// request itself:
+//
+// let router = Router::new()
+//     .get("/", index)
+//     .wrap(|request: Request, next: Next| {
+//         if request.headers.contains("Authorization") {
+//             next.run(request).header("Cache-Control", "private")
+//         } else {
+//             Response::new(StatusCode::Unauthorized)
+//         }
+//     });
+
+use crate::http::{Request, Response};
+
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> Response,
+    F: Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of a middleware chain. Running it hands the request to the next middleware, or to the
+/// handler once there are no middlewares left.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    endpoint: &'a dyn Fn(Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Box<dyn Middleware>],
+        endpoint: &'a dyn Fn(Request) -> Response,
+    ) -> Self {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    pub fn run(self, request: Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, StatusCode};
+    use crate::router::{Handler, Router};
+    use std::sync::{Arc, Mutex};
+
+    fn request(target: &str, headers: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", target, headers);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn runs_in_the_order_added() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (first, second) = (Arc::clone(&order), Arc::clone(&order));
+
+        let router = Router::new()
+            .get("/", |_| Response::text(StatusCode::Ok, "index"))
+            .wrap(move |request: Request, next: Next| {
+                first.lock().unwrap().push("first in");
+                let response = next.run(request);
+                first.lock().unwrap().push("first out");
+                response
+            })
+            .wrap(move |request: Request, next: Next| {
+                second.lock().unwrap().push("second in");
+                let response = next.run(request);
+                second.lock().unwrap().push("second out");
+                response
+            });
+
+        router.handle(request("/", ""));
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["first in", "second in", "second out", "first out"]
+        );
+    }
+
+    #[test]
+    fn can_answer_without_the_handler() {
+        let router = Router::new()
+            .get("/", |_| Response::text(StatusCode::Ok, "secret"))
+            .wrap(|request: Request, next: Next| {
+                if request.headers.contains("Authorization") {
+                    next.run(request)
+                } else {
+                    Response::new(StatusCode::Unauthorized)
+                }
+            });
+
+        assert_eq!(
+            router.handle(request("/", "")).status,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            router.handle(request("/", "Authorization: yes\r\n")).status,
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn can_rewrite_requests_and_responses() {
+        // Serve everything under /v1 from the routes without it, and tag the responses.
+        let router = Router::new()
+            .get("/posts/:id", |request: Request| {
+                Response::text(StatusCode::Ok, request.param("id").unwrap())
+            })
+            .post("/posts", |_| Response::new(StatusCode::Created))
+            .wrap(|mut request: Request, next: Next| {
+                if let Some(rest) = request.target.strip_prefix("/v1") {
+                    request.target = rest.to_string();
+                }
+                next.run(request).header("X-Api-Version", "1")
+            });
+
+        let response = router.handle(request("/v1/posts/7", ""));
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("X-Api-Version"), Some("1"));
+
+        // Requests that don't match a route still go through the middleware.
+        let response = router.handle(Request {
+            method: Method::Post,
+            ..request("/v1/posts/7", "")
+        });
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("X-Api-Version"), Some("1"));
+    }
+}
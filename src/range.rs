@@ -0,0 +1,322 @@
+// Range requests, which let a client ask for only part of a file, e.g. to resume a download or to
+// seek in a video:
+//
+// Range: bytes=0-499          the first 500 bytes
+// Range: bytes=500-           everything from byte 500 on
+// Range: bytes=-500           the last 500 bytes
+// Range: bytes=0-99,200-299   two parts, sent back as multipart/byteranges
+//
+// Ranges are inclusive in the header, but we use half-open `Range<u64>`s everywhere else.
+
+use crate::http::{Body, Response, StatusCode};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// More ranges than this in one request are more likely to be an attempt to make us do a lot of
+/// work than a real client, so we ignore the Range header and send the whole file.
+pub const MAX_RANGES: usize = 16;
+
+/// What a Range header asks for, given the length of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    /// The header isn't one we understand, so it should be ignored and the whole file sent.
+    Ignore,
+    /// The parts of the file to send, sorted, with overlapping ranges merged.
+    Satisfiable(Vec<Range<u64>>),
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Parses a Range header for a file of `length` bytes.
+pub fn parse(header: &str, length: u64) -> Ranges {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ranges::Ignore,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return Ranges::Ignore,
+        };
+        let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+        let number = |s: &str| -> Option<u64> {
+            if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+                s.parse().ok()
+            } else {
+                None
+            }
+        };
+
+        let range = match (number(first), number(last)) {
+            // 500-999, where the end can be past the end of the file.
+            (Some(first), Some(last)) if first <= last => first..last.saturating_add(1).min(length),
+            // 500-
+            (Some(first), None) if last.is_empty() => first..length,
+            // -500, the last 500 bytes.
+            (None, Some(suffix)) if first.is_empty() => length.saturating_sub(suffix)..length,
+            _ => return Ranges::Ignore,
+        };
+
+        // A range starting past the end of the file can't be satisfied, but the others might be.
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Ignore;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    // Overlapping or touching ranges are merged, so no byte is sent twice.
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ranges::Satisfiable(merged)
+}
+
+/// Answers a range request for a file with 206 Partial Content. A single range is sent as it is,
+/// while several are sent as a multipart/byteranges body, each part with its own headers.
+pub fn partial_content(
+    file: File,
+    length: u64,
+    content_type: &str,
+    ranges: &[Range<u64>],
+) -> Response {
+    let response = Response::new(StatusCode::PartialContent);
+
+    if let [range] = ranges {
+        let body = Parts::new(file, vec![Part::File(range.clone())]);
+        return response
+            .header("Content-Type", content_type)
+            .header("Content-Range", &content_range(range, length))
+            .body(Body::Sized(Box::new(body), range.end - range.start));
+    }
+
+    // --BOUNDARY
+    // Content-Type: text/plain
+    // Content-Range: bytes 0-99/1000
+    //
+    // ...the first hundred bytes...
+    // --BOUNDARY
+    // ...
+    // --BOUNDARY--
+
+    let boundary = boundary();
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            content_range(range, length)
+        );
+        parts.push(Part::Bytes(Cursor::new(head.into_bytes())));
+        parts.push(Part::File(range.clone()));
+    }
+    let end = format!("\r\n--{}--\r\n", boundary);
+    parts.push(Part::Bytes(Cursor::new(end.into_bytes())));
+
+    let body = Parts::new(file, parts);
+    let body_length = body.len();
+    response
+        .header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .body(Body::Sized(Box::new(body), body_length))
+}
+
+/// Answers a range request that asks only for bytes past the end of the file.
+pub fn not_satisfiable(length: u64) -> Response {
+    Response::text(StatusCode::RangeNotSatisfiable, "Range Not Satisfiable\n")
+        .header("Content-Range", &format!("bytes */{}", length))
+}
+
+fn content_range(range: &Range<u64>, length: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, length)
+}
+
+// A boundary has to be something that doesn't appear in any of the parts. A long, unpredictable
+// string is as close as we can get without reading them.
+
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", nanos, count)
+}
+
+enum Part {
+    Bytes(Cursor<Vec<u8>>),
+    File(Range<u64>),
+}
+
+// Reads the parts of a response body one after the other. File parts all come from the same file,
+// so we seek to the start of each one as we get to it.
+
+struct Parts {
+    file: File,
+    parts: VecDeque<Part>,
+    /// Whether we've seeked to the start of the file part at the front.
+    seeked: bool,
+}
+
+impl Parts {
+    fn new(file: File, parts: Vec<Part>) -> Self {
+        Parts {
+            file,
+            parts: parts.into(),
+            seeked: false,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Bytes(bytes) => bytes.get_ref().len() as u64,
+                Part::File(range) => range.end - range.start,
+            })
+            .sum()
+    }
+}
+
+impl Read for Parts {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let bytes_read = match self.parts.front_mut() {
+                None => return Ok(0),
+                Some(Part::Bytes(bytes)) => bytes.read(buf)?,
+                Some(Part::File(range)) => {
+                    if !self.seeked {
+                        self.file.seek(SeekFrom::Start(range.start))?;
+                        self.seeked = true;
+                    }
+                    let left = range.end - range.start;
+                    let max = buf.len().min(left as usize);
+                    let bytes_read = self.file.read(&mut buf[..max])?;
+                    if bytes_read == 0 && left > 0 {
+                        // The file has shrunk since we looked at it.
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    range.start += bytes_read as u64;
+                    bytes_read
+                }
+            };
+
+            if bytes_read > 0 || buf.is_empty() {
+                return Ok(bytes_read);
+            }
+            self.parts.pop_front();
+            self.seeked = false;
+        }
+    }
+}
+
+// Lists with a single range in them are exactly what the tests mean, not a typo for a list of the
+// numbers in that range.
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use std::fs;
+
+    #[test]
+    fn parses_ranges() {
+        let cases = &[
+            ("bytes=0-499", Ranges::Satisfiable(vec![0..500])),
+            ("bytes=500-", Ranges::Satisfiable(vec![500..1000])),
+            ("bytes=-300", Ranges::Satisfiable(vec![700..1000])),
+            ("bytes=-5000", Ranges::Satisfiable(vec![0..1000])),
+            ("bytes=900-5000", Ranges::Satisfiable(vec![900..1000])),
+            (
+                "bytes=500-599, 0-99,50-149 ,2000-",
+                Ranges::Satisfiable(vec![0..150, 500..600]),
+            ),
+            ("bytes=1000-", Ranges::Unsatisfiable),
+            ("bytes=-0", Ranges::Unsatisfiable),
+            ("bytes=5-1", Ranges::Ignore),
+            ("bytes=abc", Ranges::Ignore),
+            ("bytes=1-+2", Ranges::Ignore),
+            ("lines=1-2", Ranges::Ignore),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(parse(header, 1000), *expected, "{}", header);
+        }
+
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&too_many, 1000), Ranges::Ignore);
+    }
+
+    fn file(dir: &TempDir, contents: &[u8]) -> File {
+        let path = dir.join("file");
+        fs::write(&path, contents).unwrap();
+        File::open(path).unwrap()
+    }
+
+    fn send(response: Response) -> (Response, Vec<u8>) {
+        let mut out = Vec::new();
+        let body = match response.body {
+            Body::Sized(reader, length) => {
+                reader.take(length).read_to_end(&mut out).unwrap();
+                Body::Empty
+            }
+            body => body,
+        };
+        (Response { body, ..response }, out)
+    }
+
+    #[test]
+    fn sends_a_single_range() {
+        let dir = TempDir::new("range-single");
+        let response = partial_content(file(&dir, b"0123456789"), 10, "text/plain", &[3..6]);
+        assert_eq!(response.body.len(), Some(3));
+
+        let (response, body) = send(response);
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 3-5/10"));
+        assert_eq!(body, b"345");
+    }
+
+    #[test]
+    fn sends_several_ranges_as_multipart() {
+        let dir = TempDir::new("range-multi");
+        let response = partial_content(file(&dir, b"0123456789"), 10, "text/plain", &[0..2, 8..10]);
+        let length = response.body.len().unwrap();
+
+        let (response, body) = send(response);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+        assert_eq!(length, expected.len() as u64);
+    }
+}
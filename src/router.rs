@@ -0,0 +1,384 @@
+// Dispatches requests to handlers based on their method and path.
+//
+// Patterns are made up of segments separated by slashes. Each segment is either matched literally,
+// or captures a parameter that the handler can get at with `Request::param`:
+//
+// /posts/:id      matches /posts/42, with id = "42"
+// /static/*path   matches /static/css/site.css, with path = "css/site.css"
+//
+// A `*` parameter captures the rest of the path, and so can only be the last segment.
+//
+// HEAD requests are answered by the GET route for the path, unless there's a HEAD route of its
+// own, and the connection leaves the body off when it sends the response. OPTIONS requests are
+// answered with the methods the path can be requested with, unless there's an OPTIONS route.
+//
+// Middleware added with `wrap` runs around every request the router gets, whether or not it
+// matches a route.
+
+use crate::http::{Method, Request, Response, StatusCode};
+use crate::middleware::{Middleware, Next};
+
+/// Anything that can turn a request into a response. Handlers are shared between all of the
+/// server's threads, which is why they need to be Send and Sync.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response,
+    F: Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Box<dyn Handler>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Router {
+    /// A router without any routes, which answers everything with a plain 404.
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            fallback: Box::new(|_| Response::text(StatusCode::NotFound, "Not Found\n")),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Adds a route. Routes are tried in the order they were added, so when two patterns match the
+    /// same path, the one added first wins.
+    pub fn route<H: Handler>(mut self, method: Method, pattern: &str, handler: H) -> Self {
+        let segments = split(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let rest = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Rest(_)));
+        assert!(
+            rest.is_none() || rest == Some(segments.len() - 1),
+            "a * parameter must be the last segment of {:?}",
+            pattern
+        );
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Replaces the handler for paths that don't match any route.
+    pub fn fallback<H: Handler>(mut self, handler: H) -> Self {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Adds a middleware. Middlewares run in the order they were added, so the first one sees the
+    /// request first and the response last.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    // Finds the route for a request, once it has been through the middleware.
+
+    fn dispatch(&self, mut request: Request) -> Response {
+        // Remember the methods of routes whose pattern matched, so that if none of them have the
+        // right method we can say which ones would have worked.
+
+        let mut allowed: Vec<&Method> = Vec::new();
+        let mut get = None;
+
+        for route in &self.routes {
+            let params = match captures(&route.segments, request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if route.method == request.method {
+                request.params = params;
+                return route.handler.handle(request);
+            }
+            if route.method == Method::Get && get.is_none() {
+                get = Some((route, params));
+            }
+            if !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+        }
+
+        if let (Method::Head, Some((route, params))) = (&request.method, get) {
+            request.params = params;
+            return route.handler.handle(request);
+        }
+
+        // `OPTIONS *` asks about the server as a whole, rather than any one path.
+
+        if request.method == Method::Options && request.target == "*" {
+            let all = self.routes.iter().map(|route| &route.method);
+            return Response::new(StatusCode::NoContent).header("Allow", &allow(all));
+        }
+
+        // 501 is for methods we don't handle anywhere, while 405 says this path doesn't take the
+        // method, but others do.
+
+        if !self.supports(&request.method) {
+            return Response::text(StatusCode::NotImplemented, "Not Implemented\n");
+        }
+        if allowed.is_empty() {
+            return self.fallback.handle(request);
+        }
+
+        let allow = allow(allowed.into_iter());
+        if request.method == Method::Options {
+            return Response::new(StatusCode::NoContent).header("Allow", &allow);
+        }
+        Response::text(StatusCode::MethodNotAllowed, "Method Not Allowed\n").header("Allow", &allow)
+    }
+
+    // Whether any route takes the method. Every server has to handle GET and HEAD, and the router
+    // answers OPTIONS itself, so those always count.
+
+    fn supports(&self, method: &Method) -> bool {
+        matches!(method, Method::Get | Method::Head | Method::Options)
+            || self.routes.iter().any(|route| route.method == *method)
+    }
+}
+
+// The value for an Allow header, from the methods of the routes that match. HEAD goes wherever GET
+// does, and OPTIONS goes everywhere.
+
+fn allow<'a>(methods: impl Iterator<Item = &'a Method>) -> String {
+    let mut allowed: Vec<&str> = Vec::new();
+    for method in methods {
+        let names: &[&str] = match method {
+            Method::Get => &["GET", "HEAD"],
+            method => &[method.as_str()][..],
+        };
+        for name in names {
+            if !allowed.contains(name) {
+                allowed.push(name);
+            }
+        }
+    }
+    if !allowed.contains(&"OPTIONS") {
+        allowed.push("OPTIONS");
+    }
+    allowed.join(", ")
+}
+
+impl Handler for Router {
+    fn handle(&self, request: Request) -> Response {
+        Next::new(&self.middleware, &|request| self.dispatch(request)).run(request)
+    }
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+// Matches a path against a route's segments, returning the captured parameters if it matches.
+
+fn captures(segments: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let mut parts = path.split('/');
+    let mut params = Vec::new();
+
+    // Keep track of where we are in the path, so that a * parameter can capture the rest of it
+    // exactly as it was sent.
+
+    let mut offset = 0;
+
+    for segment in segments {
+        if let Segment::Rest(name) = segment {
+            params.push((name.clone(), path.get(offset..).unwrap_or("").to_string()));
+            return Some(params);
+        }
+
+        let part = parts.next()?;
+        offset += part.len() + 1;
+
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Param(name) if !part.is_empty() => {
+                params.push((name.clone(), part.to_string()))
+            }
+            _ => return None,
+        }
+    }
+
+    // Every part of the path has to be matched by a segment.
+    match parts.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out[out.find("\r\n\r\n").unwrap() + 4..].to_string()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_| Response::text(StatusCode::Ok, "index"))
+            .get("/posts/:id", |request: Request| {
+                Response::text(
+                    StatusCode::Ok,
+                    &format!("post {}", request.param("id").unwrap()),
+                )
+            })
+            .delete("/posts/:id", |_| Response::new(StatusCode::NoContent))
+            .post("/comments", |_| Response::new(StatusCode::Created))
+            .get("/static/*path", |request: Request| {
+                Response::text(StatusCode::Ok, request.param("path").unwrap())
+            })
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        let router = router();
+
+        assert_eq!(body(router.handle(request(Method::Get, "/"))), "index");
+        assert_eq!(
+            body(router.handle(request(Method::Get, "/posts/42?draft=1"))),
+            "post 42"
+        );
+        assert_eq!(
+            router.handle(request(Method::Delete, "/posts/42")).status,
+            StatusCode::NoContent
+        );
+        assert_eq!(
+            body(router.handle(request(Method::Get, "/static/css/site.css"))),
+            "css/site.css"
+        );
+        assert_eq!(body(router.handle(request(Method::Get, "/static/"))), "");
+    }
+
+    #[test]
+    fn unmatched_paths_fall_back() {
+        let router = router();
+
+        for target in &["/posts", "/posts/", "/posts/42/comments", "/nope"] {
+            assert_eq!(
+                router.handle(request(Method::Get, target)).status,
+                StatusCode::NotFound,
+                "{}",
+                target
+            );
+        }
+
+        let router = router.fallback(|_| Response::text(StatusCode::NotFound, "custom"));
+        assert_eq!(body(router.handle(request(Method::Get, "/nope"))), "custom");
+    }
+
+    #[test]
+    fn wrong_method_is_not_allowed() {
+        let response = router().handle(request(Method::Post, "/posts/42"));
+
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        // Methods that no route takes aren't implemented at all.
+        let response = router().handle(request(Method::Put, "/posts/42"));
+        assert_eq!(response.status, StatusCode::NotImplemented);
+        let response = router().handle(request(Method::Other("BREW".to_string()), "/"));
+        assert_eq!(response.status, StatusCode::NotImplemented);
+    }
+
+    #[test]
+    fn answers_head_and_options() {
+        let router = router().route(Method::Head, "/posts/:id", |_| {
+            Response::new(StatusCode::Ok).header("X-Head", "yes")
+        });
+
+        let response = router.handle(request(Method::Head, "/static/site.css"));
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(body(response), "site.css");
+        let response = router.handle(request(Method::Head, "/posts/1"));
+        assert_eq!(response.headers.get("X-Head"), Some("yes"));
+        assert_eq!(
+            router.handle(request(Method::Head, "/nope")).status,
+            StatusCode::NotFound
+        );
+
+        let response = router.handle(request(Method::Options, "/posts/1"));
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+        let response = router.handle(request(Method::Options, "*"));
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, DELETE, POST, OPTIONS")
+        );
+        assert_eq!(
+            router.handle(request(Method::Options, "/nope")).status,
+            StatusCode::NotFound
+        );
+    }
+}
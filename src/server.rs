@@ -0,0 +1,607 @@
+// A multi-threaded HTTP/1.1 server. Each address gets a thread of its own accepting connections,
+// which are handled on a thread pool, so a slow request only ties up one worker. Every request is
+// answered by a Router:
+//
+// let server = HttpServer::bind("127.0.0.1:7878")
+//     .router(Router::new().get("/", |_| Response::text(StatusCode::Ok, "Hello!")))
+//     .workers(4)
+//     .spawn()?;
+//
+// The server runs in the background until its handle is shut down or dropped. Binding to port 0
+// lets the operating system pick a free port, which `local_addr` then tells us, so tests can run
+// a server each without tripping over one another.
+
+use crate::access_log::{AccessLog, Entry, RequestInfo};
+use crate::config::Config;
+use crate::error::{ErrorPages, ServerError};
+use crate::http::{Limits, Method, ReadError, RequestReader, StatusCode, Version};
+use crate::router::{Handler, Router};
+use crate::thread_pool::{DropPolicy, ThreadPool, DEFAULT_LANE};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Sets up a server before it starts listening. Everything but the addresses has a default: four
+/// workers, the connection settings from `Config::default()`, a router that answers everything
+/// with 404, plain text error pages, and no access log.
+pub struct HttpServer {
+    addrs: io::Result<Vec<SocketAddr>>,
+    config: Config,
+    router: Router,
+    error_pages: ErrorPages,
+    access_log: AccessLog,
+}
+
+impl HttpServer {
+    /// A server that listens on `addr`, or on every address it resolves to, such as each of a
+    /// `&[SocketAddr]`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Self {
+        HttpServer {
+            addrs: addr.to_socket_addrs().map(Iterator::collect),
+            config: Config::default(),
+            router: Router::new(),
+            error_pages: ErrorPages::new(),
+            access_log: AccessLog::disabled(),
+        }
+    }
+
+    /// Takes the settings for connections, like the number of workers and the timeouts, from
+    /// `config`. Its addresses are ignored in favour of the ones given to `bind`, and so are the
+    /// settings for what's served, like the document root, which are up to the router.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// How many threads handle connections.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// The pages sent when a request can't be read, a handler panics, or we're too busy to take
+    /// a connection. Error responses from the router are up to the router.
+    pub fn error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.error_pages = error_pages;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Starts listening, and handling connections in the background.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        // Settings made in code haven't been through `Config::validate`, and some of them would
+        // otherwise only show up later, as a panic or as connections closing for no reason.
+
+        let config = &self.config;
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        let timeouts = [
+            config.idle_timeout,
+            config.header_timeout,
+            config.body_timeout,
+            config.write_timeout,
+        ];
+        if config.workers == 0 {
+            return invalid("there must be at least one worker");
+        } else if timeouts.contains(&Duration::from_secs(0)) {
+            return invalid("connection timeouts can't be zero");
+        } else if config.max_requests == 0 {
+            return invalid("max_requests must be at least 1");
+        }
+
+        let addrs = self.addrs?;
+        if addrs.is_empty() {
+            return invalid("there must be at least one address to bind to");
+        }
+
+        let listeners = addrs
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr).map_err(|err| {
+                    io::Error::new(err.kind(), format!("unable to listen on {}: {}", addr, err))
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Create a thread pool that executes connections asynchronously. There are never more
+        // than `workers` threads created, so our system won’t get overloaded if the server
+        // receives a lot of requests. If a request takes a long time, the server will be able to
+        // serve other requests by having another thread run them.
+
+        // Using a thread pool is just one of many ways to improve the throughput of a web server.
+        // Other options are the fork/join model and the single-threaded async I/O model.
+
+        // When the pool is dropped at shutdown, it waits for the connections it's already working
+        // on, but only for so long: a client that refuses to go away shouldn't keep the server up.
+
+        let config = self.config;
+        let pool = ThreadPool::builder(config.workers)
+            .lane(DEFAULT_LANE, 0, config.queue_limit)
+            .drop_policy(DropPolicy::JoinWithTimeout(config.shutdown_timeout))
+            .build();
+
+        // Every connection is handled on one of the pool's threads, so they all need to share the
+        // server's state. Arc lets them do that without copying it.
+
+        let server = Arc::new(Server {
+            config,
+            router: self.router,
+            error_pages: self.error_pages,
+            access_log: self.access_log,
+            shutting_down: AtomicBool::new(false),
+            connections: AtomicUsize::new(0),
+        });
+
+        // Each address gets a thread of its own waiting for connections, which hands them over to
+        // the dispatcher. The channel closes once every one of those threads has finished.
+
+        let (connections, incoming) = mpsc::channel();
+        for listener in listeners {
+            let connections = connections.clone();
+            let server = Arc::clone(&server);
+            thread::spawn(move || accept(listener, connections, server));
+        }
+        drop(connections);
+
+        let dispatcher = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || dispatch(incoming, pool, server))
+        };
+
+        Ok(ServerHandle {
+            server,
+            addrs,
+            dispatcher: Some(dispatcher),
+        })
+    }
+}
+
+/// A running server. Dropping the handle shuts the server down, just like `shutdown`.
+pub struct ServerHandle {
+    server: Arc<Server>,
+    addrs: Vec<SocketAddr>,
+    dispatcher: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on, or the first of them if there are several. When
+    /// it was bound to port 0, this says which port it got.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Every address the server is listening on.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Shuts the server down gracefully: it stops accepting connections, and lets the ones it has
+    /// finish what they're doing, for up to the shutdown timeout.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    // The threads accepting connections are blocked waiting for one, and won't notice that we're
+    // shutting down until they get one, so we connect to each of our addresses to wake them.
+    //
+    // An address like 0.0.0.0 means "every address" to listen on, but isn't one we can portably
+    // connect to, so we go through loopback instead. And if the listener's backlog is full, the
+    // connection can't be completed until it's accepted anyway, so we don't wait around for it.
+
+    fn stop(&mut self) {
+        let dispatcher = match self.dispatcher.take() {
+            Some(dispatcher) => dispatcher,
+            None => return,
+        };
+
+        self.server.shutting_down.store(true, Ordering::SeqCst);
+        for addr in &self.addrs {
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+        let _ = dispatcher.join();
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Everything the threads handling connections share.
+struct Server {
+    config: Config,
+    router: Router,
+    error_pages: ErrorPages,
+    access_log: AccessLog,
+    shutting_down: AtomicBool,
+    /// Connections being handled, or waiting for a worker.
+    connections: AtomicUsize,
+}
+
+/// A connection's place in the count of open connections, given up when it's dropped.
+struct Slot(Arc<Server>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Hands each connection over to the pool, until every listener has stopped accepting them. Then
+/// waits for the pool to finish the connections it's working on.
+fn dispatch(incoming: Receiver<(TcpStream, Slot)>, pool: ThreadPool, server: Arc<Server>) {
+    for (stream, slot) in incoming {
+        // With a queue limit, the pool itself can run out of room for connections. We hang on to
+        // a second handle to the connection, so that we can still turn it away if that happens.
+
+        let spare = stream.try_clone();
+        let queued = {
+            let server = Arc::clone(&server);
+            pool.execute_in(DEFAULT_LANE, move || {
+                handle_connection(stream, &server);
+                drop(slot);
+            })
+        };
+        match (queued, spare) {
+            (Ok(()), _) => {}
+            (Err(_), Ok(stream)) => reject(stream, &server),
+            (Err(err), Err(_)) => println!("dropping a connection: {}", err),
+        }
+    }
+
+    println!(
+        "Shutting down; waiting up to {:?} for open connections.",
+        server.config.shutdown_timeout
+    );
+    drop(pool);
+    println!("Shut down.");
+}
+
+/// Accepts connections on one listener until we start shutting down. Returning drops the listener,
+/// which closes it, so new clients are turned away rather than left waiting for an accept that
+/// will never come.
+fn accept(listener: TcpListener, connections: Sender<(TcpStream, Slot)>, server: Arc<Server>) {
+    for stream in listener.incoming() {
+        if server.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("unable to accept a connection: {}", err);
+                continue;
+            }
+        };
+
+        // Once we have as many connections as we're allowed, queueing any more would only make
+        // every client wait longer. It's better to tell the extra ones to come back later, right
+        // away, without bothering the pool.
+
+        let open = server.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(Arc::clone(&server));
+        if server.config.max_connections.is_some_and(|max| open >= max) {
+            drop(slot);
+            reject(stream, &server);
+            continue;
+        }
+
+        if connections.send((stream, slot)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Turns a connection away with 503 Service Unavailable, because we're too busy to handle it,
+/// without ever blocking.
+fn reject(stream: TcpStream, server: &Server) {
+    let (time, started) = (SystemTime::now(), Instant::now());
+
+    // This runs on the thread accepting connections, just when we're busiest, so nothing here
+    // can wait on the client. The connection is left non-blocking throughout.
+
+    // Closing a connection with unread data in it resets the connection, which can lose the
+    // response before the client reads it. So we read whatever the client has sent so far, but
+    // without waiting for more.
+
+    let _ = stream.set_nonblocking(true);
+    let _ = (&stream).read(&mut [0; 4096]);
+
+    // The response is small enough to fit in the socket's send buffer, so it normally goes out in
+    // full straight away. If it doesn't, the client is too slow to be worth waiting for, and gets
+    // whatever made it.
+
+    let response = server
+        .error_pages
+        .render(StatusCode::ServiceUnavailable)
+        .header(
+            "Retry-After",
+            &server.config.retry_after.as_secs().max(1).to_string(),
+        )
+        .header("Connection", "close");
+    let written = response.write_to(&mut &stream);
+
+    if let Ok(client) = stream.peer_addr() {
+        server.access_log.log(Entry {
+            client,
+            time,
+            request: None,
+            status: StatusCode::ServiceUnavailable,
+            bytes: written.unwrap_or(0),
+            latency: started.elapsed(),
+        });
+    }
+}
+
+/// Handles the HTTP requests on a connection, and returns their responses. Both the requests and
+/// responses are read and written from/to the TCP streeam.
+///
+/// HTTP is a text-based protocol, and a request takes this format:
+///
+/// Method Request-URI HTTP-Version CRLF
+/// headers CRLF
+/// message-body
+///
+/// Responses have the following format:
+///
+/// HTTP-Version Status-Code Reason-Phrase CRLF
+/// headers CRLF
+/// message-body
+fn handle_connection(stream: TcpStream, server: &Server) {
+    let config = &server.config;
+
+    // Read the tcp stream. If accessed from a browser or curl, this should set the following value
+    // into our buf:
+
+    // GET / HTTP/1.1
+    // Host: localhost:7878
+    // User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:78.0) Gecko/20100101 Firefox/78.0
+    // Accept: text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8
+    // Accept-Language: en-US,en;q=0.5
+    // Accept-Encoding: gzip, deflate
+    // Connection: keep-alive
+    // Upgrade-Insecure-Requests: 1
+
+    // or with `curl http://localhost:7878/`
+    // GET / HTTP/1.1
+    // Host: localhost:7878
+    // User-Agent: curl/7.64.1
+    // Accept: */*
+
+    // A single read isn't guaranteed to return the whole request: TCP is a stream of bytes, and a
+    // client is free to send its request in as many pieces as it likes. The RequestReader keeps
+    // reading, in blocks, until it has the whole request, body included. Rather than silently
+    // truncating a request that is too large, it tells us so, and we can tell the client.
+
+    // A client that keeps its connection open can send request after request down it, without
+    // waiting for the response to each one (pipelining). We answer them one at a time, in the
+    // order they arrived, which is exactly the order HTTP requires the responses to be in.
+    // Whatever the reader has buffered past the end of one request is the start of the next.
+
+    // Every connection ties up one of our threads, so none of them can be allowed to last forever.
+    // Connections that go quiet are closed after the idle timeout. Clients that take too long to
+    // send a request, however busy they look doing it, get 408 Request Timeout. Clients that take
+    // too long to receive a response are cut off. And a connection is closed after max_requests,
+    // even if the client is still busy.
+
+    let limits = Limits {
+        idle_timeout: Some(config.idle_timeout),
+        header_timeout: Some(config.header_timeout),
+        body_timeout: Some(config.body_timeout),
+        ..Limits::default()
+    };
+    if let Err(err) = stream.set_write_timeout(Some(config.write_timeout)) {
+        println!("unable to set a timeout on the connection: {}", err);
+        return;
+    }
+
+    // Each response's head and body go out in separate writes. Without this, Nagle's algorithm
+    // would hold back the start of the next response until the client acknowledged the last one.
+
+    let _ = stream.set_nodelay(true);
+
+    let client = match stream.peer_addr() {
+        Ok(client) => client,
+        // The client has already gone.
+        Err(_) => return,
+    };
+
+    let mut reader = RequestReader::new(&stream, limits);
+    let mut writer = &stream;
+
+    for served in 1..=config.max_requests {
+        let read = reader.read_request();
+        let (time, started) = (SystemTime::now(), Instant::now());
+        let mut info = None;
+        let mut head = false;
+        let mut chunks = true;
+
+        let (response, keep_alive) = match read {
+            Ok(Some(request)) => {
+                let keep_alive = request.keep_alive() && served < config.max_requests;
+                info = Some(RequestInfo::from(&request));
+                head = request.method == Method::Head;
+
+                // HTTP/1.0 clients don't understand chunks, so a body of unknown length can only
+                // end with the connection.
+
+                chunks = request.version == Version::Http11;
+
+                // A handler that panics only takes its own request down with it. The client gets
+                // a 500, and the worker carries on.
+
+                let response =
+                    panic::catch_unwind(AssertUnwindSafe(|| server.router.handle(request)))
+                        .unwrap_or_else(|_| {
+                            server.error_pages.render(StatusCode::InternalServerError)
+                        });
+                (response, keep_alive)
+            }
+            // The client hung up, or had nothing more to say before the idle timeout. Either way
+            // there's no request to answer, so the connection is closed without a response.
+            Ok(None) | Err(ReadError::IdleTimeout) => return,
+            Err(err @ ReadError::Io(_)) | Err(err @ ReadError::UnexpectedEof) => {
+                let err = ServerError::from(err);
+                if err.is_disconnect() {
+                    println!("{} disconnected in the middle of a request", client);
+                } else {
+                    println!("unable to read request: {}", err);
+                }
+                return;
+            }
+            // After a bad request, there's no telling where the next one would start.
+            Err(err) => (server.error_pages.render(err.status_code()), false),
+        };
+
+        // Let the client know whether it can send another request. HTTP/1.1 clients assume they
+        // can unless told otherwise, but it does no harm to say so.
+
+        // Once we're shutting down, each connection is closed as soon as its current request has
+        // been answered. Connections waiting for their next request are closed by the idle
+        // timeout.
+
+        let keep_alive = keep_alive
+            && !response.closes_connection()
+            && (chunks || !response.is_chunked())
+            && !server.shutting_down.load(Ordering::SeqCst);
+        let response = if keep_alive {
+            response.header("Connection", "keep-alive")
+        } else {
+            response.header("Connection", "close")
+        };
+
+        // write_to writes the status line, headers and body straight to the stream, which sends
+        // those bytes down the connection. A HEAD request gets the same response as a GET, but
+        // only the status line and headers are sent.
+
+        let status = response.status;
+        let written = if head {
+            response.write_head_to(&mut writer)
+        } else {
+            response.write_to(&mut writer)
+        };
+        let written = written.and_then(|bytes| writer.flush().map(|_| bytes));
+
+        server.access_log.log(Entry {
+            client,
+            time,
+            request: info,
+            status,
+            bytes: *written.as_ref().unwrap_or(&0),
+            latency: started.elapsed(),
+        });
+
+        if let Err(err) = written {
+            let err = ServerError::from(err);
+            if err.is_disconnect() {
+                println!("{} disconnected before the response was sent", client);
+            } else {
+                println!("unable to write the response: {}", err);
+            }
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Body, Response};
+
+    #[test]
+    fn serves_on_an_ephemeral_port() {
+        let router = Router::new()
+            .get("/", |_| Response::text(StatusCode::Ok, "Hello!"))
+            .get("/chunks", |_| {
+                Response::new(StatusCode::Ok).body(Body::chunks(vec!["one", "two"]))
+            });
+        let server = HttpServer::bind("127.0.0.1:0")
+            .router(router)
+            .workers(2)
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        // Both responses come back on the same connection.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /chunks HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n3\r\none\r\n3\r\ntwo\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nHello!"));
+
+        // Once it has shut down, nothing is listening any more.
+        server.shutdown();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn reports_addresses_it_cannot_listen_on() {
+        let first = HttpServer::bind("127.0.0.1:0").spawn().unwrap();
+        let err = HttpServer::bind(first.local_addr()).spawn().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().contains("unable to listen on"));
+    }
+
+    #[test]
+    fn shuts_down_when_listening_on_every_address() {
+        let server = HttpServer::bind("0.0.0.0:0").spawn().unwrap();
+        let started = Instant::now();
+        server.shutdown();
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn refuses_settings_it_cannot_run_with() {
+        let err = HttpServer::bind("127.0.0.1:0")
+            .workers(0)
+            .spawn()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let config = Config {
+            write_timeout: Duration::from_secs(0),
+            ..Config::default()
+        };
+        let err = HttpServer::bind("127.0.0.1:0")
+            .config(config)
+            .spawn()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "connection timeouts can't be zero");
+    }
+}
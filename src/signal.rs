@@ -0,0 +1,138 @@
+// Catches Unix signals, such as the SIGINT sent by Ctrl-C, so that a program can shut down
+// cleanly instead of being killed on the spot.
+//
+// A signal handler runs in the middle of whatever the interrupted thread happened to be doing, so
+// there's almost nothing it can safely do: no locks, no allocation, no printing. What it can do is
+// write to a file descriptor. So the handler writes the signal's number into a pipe (the
+// "self-pipe trick"), and an ordinary thread reads it out of the other end, where it's free to do
+// whatever it likes.
+//
+// We don't depend on the libc crate, but the standard library already links against libc on Unix,
+// so declaring the handful of functions we need is enough to call them.
+
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+pub const SIGINT: c_int = 2;
+pub const SIGTERM: c_int = 15;
+
+extern "C" {
+    fn pipe(fds: *mut c_int) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+
+    // Where the calling thread's errno lives. Every libc has a function for this, but they don't
+    // agree on its name.
+
+    #[cfg_attr(
+        any(target_os = "linux", target_os = "redox"),
+        link_name = "__errno_location"
+    )]
+    #[cfg_attr(
+        any(target_os = "android", target_os = "openbsd", target_os = "netbsd"),
+        link_name = "__errno"
+    )]
+    #[cfg_attr(
+        any(target_os = "macos", target_os = "ios", target_os = "freebsd"),
+        link_name = "__error"
+    )]
+    fn errno_location() -> *mut c_int;
+}
+
+// signal() returns this when it fails.
+const SIG_ERR: usize = usize::MAX;
+
+// The handler can only get at globals, so this is where it finds the write end of the pipe. There
+// is only ever one pipe, however many times `Signals::install` is called.
+
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+static READ_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signum: c_int) {
+    let byte = signum as u8;
+    let fd = WRITE_FD.load(Ordering::SeqCst);
+
+    // The write sets errno if it fails, and the code we interrupted may be just about to look at
+    // errno for a failure of its own. So put it back the way we found it.
+
+    unsafe {
+        let errno = *errno_location();
+        write(fd, &byte as *const u8 as *const c_void, 1);
+        *errno_location() = errno;
+    }
+}
+
+/// The signals caught by `Signals::install`, waiting to be received.
+#[derive(Debug)]
+pub struct Signals {
+    read_fd: c_int,
+}
+
+impl Signals {
+    /// Starts catching the given signals. Until they're received with `wait`, they no longer do
+    /// whatever they would have done, e.g. SIGINT no longer ends the process.
+    pub fn install(signals: &[c_int]) -> io::Result<Signals> {
+        if READ_FD.load(Ordering::SeqCst) < 0 {
+            let mut fds = [0; 2];
+            if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Another thread may have beaten us to it, in which case our pipe is never used.
+            if READ_FD
+                .compare_exchange(-1, fds[0], Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                WRITE_FD.store(fds[1], Ordering::SeqCst);
+            }
+        }
+
+        for &signum in signals {
+            if unsafe { signal(signum, on_signal) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Signals {
+            read_fd: READ_FD.load(Ordering::SeqCst),
+        })
+    }
+
+    /// Blocks until one of the signals arrives, and returns its number.
+    pub fn wait(&self) -> io::Result<c_int> {
+        let mut byte = 0u8;
+        loop {
+            match unsafe { read(self.read_fd, &mut byte as *mut u8 as *mut c_void, 1) } {
+                1 => return Ok(c_int::from(byte)),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" {
+        fn raise(signum: c_int) -> c_int;
+    }
+
+    #[test]
+    fn receives_caught_signals() {
+        let signals = Signals::install(&[SIGTERM]).unwrap();
+
+        // Without the handler, this would kill the test run.
+        assert_eq!(unsafe { raise(SIGTERM) }, 0);
+
+        assert_eq!(signals.wait().unwrap(), SIGTERM);
+    }
+}
@@ -5,6 +5,12 @@ pub mod blog {
         state: Option<Box<dyn State>>,
     }
 
+    impl Default for Post {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl Post {
         pub fn new() -> Self {
             Post {
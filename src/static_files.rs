@@ -0,0 +1,415 @@
+// Serves files from a directory on disk.
+//
+// Mount it on a route with a `*path` parameter, and that parameter is taken as the path of the
+// file relative to the root directory:
+//
+// Router::new().get("/static/*path", StaticFiles::new("public"))
+//
+// GET /static/css/site.css then serves public/css/site.css. Without a `path` parameter, the whole
+// request path is used instead.
+//
+// Directories without an index file are answered with 404 Not Found, unless listings have been
+// turned on for the mount, in which case they get a listing of what's in them.
+//
+// Errors are answered with just their status, for `ErrorPages` to give them a body.
+
+use crate::cache::Validators;
+use crate::error::ServerError;
+use crate::http::{percent_decode, Request, Response, StatusCode};
+use crate::listing;
+use crate::range::{self, Ranges};
+use crate::router::Handler;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    listings: bool,
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`, with `index.html` standing in for directories.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            index: "index.html".to_string(),
+            listings: false,
+        }
+    }
+
+    /// Changes which file is served when a directory is requested.
+    pub fn index(mut self, name: &str) -> Self {
+        self.index = name.to_string();
+        self
+    }
+
+    /// Whether directories without an index file get a listing of their contents. Off by
+    /// default, since it shows visitors files they might otherwise never have known were there.
+    pub fn listings(mut self, enabled: bool) -> Self {
+        self.listings = enabled;
+        self
+    }
+
+    /// Works out which file a request is for, or which directory when it's to be listed, or the
+    /// response to send if it can't be served.
+    fn resolve(&self, request: &Request) -> Result<PathBuf, Response> {
+        let path = request.param("path").unwrap_or_else(|| request.path());
+
+        // Decode before looking for `..`, otherwise `%2e%2e/` would sneak straight past us.
+
+        let path = percent_decode(path)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| Response::new(StatusCode::BadRequest))?;
+
+        // Only plain file names are allowed between the slashes. A `..` could climb out of the
+        // root, and a backslash or a drive prefix means something on Windows that it doesn't
+        // mean here. A NUL byte can't be part of a path at all.
+
+        let forbidden = || Response::new(StatusCode::Forbidden);
+        if path.contains('\\') || path.contains('\0') {
+            return Err(forbidden());
+        }
+
+        let mut file = self.root.clone();
+        for component in Path::new(&path).components() {
+            match component {
+                Component::Normal(name) => file.push(name),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(forbidden()),
+            }
+        }
+
+        // A symlink inside the root could still point outside of it, so make sure the file we'd
+        // actually open is inside the root too.
+
+        let not_found = || Response::new(StatusCode::NotFound);
+        let root = self.root.canonicalize().map_err(|_| not_found())?;
+        let mut file = file.canonicalize().map_err(|_| not_found())?;
+        if !file.starts_with(&root) {
+            return Err(forbidden());
+        }
+
+        // A directory stays a directory if it's going to be listed. Either way, its page has to
+        // be served from a URL ending with a slash.
+
+        if file.is_dir() {
+            if let Some(redirect) = listing::add_slash(request) {
+                return Err(redirect);
+            }
+            let index = file.join(&self.index);
+            if index.is_file() || !self.listings {
+                file = index;
+            }
+        }
+        Ok(file)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: Request) -> Response {
+        let path = match self.resolve(&request) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
+        if path.is_dir() {
+            return listing::response(&request, &path);
+        }
+
+        // The body is sent straight from the file, so binary files are fine and large ones don't
+        // have to fit in memory.
+
+        let (file, metadata) = match File::open(&path).and_then(|file| {
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        }) {
+            Ok((file, metadata)) if metadata.is_file() => (file, metadata),
+            // Anything else, like a socket or a device, isn't something we serve.
+            Ok(_) => return Response::new(StatusCode::NotFound),
+            Err(err) => return ServerError::from(err).into(),
+        };
+        let length = metadata.len();
+        let content_type = content_type(&path);
+
+        // If the client's copy of the file is still current, there's no need to send it again.
+
+        let validators = Validators::from_metadata(&metadata);
+        if validators.is_fresh(&request) {
+            return validators.not_modified();
+        }
+
+        // Accept-Ranges tells clients they can ask for part of the file, e.g. to resume a
+        // download, rather than starting again from scratch.
+
+        let ranges = match request.headers.get("Range") {
+            Some(header) if validators.range_applies(&request) => range::parse(header, length),
+            _ => Ranges::Ignore,
+        };
+        let response = match ranges {
+            Ranges::Ignore => Response::new(StatusCode::Ok)
+                .header("Content-Type", content_type)
+                .body(file),
+            Ranges::Satisfiable(ranges) => {
+                range::partial_content(file, length, content_type, &ranges)
+            }
+            Ranges::Unsatisfiable => range::not_satisfiable(length),
+        };
+        validators.apply(response.header("Accept-Ranges", "bytes"))
+    }
+}
+
+/// Guesses a file's Content-Type from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("webp") => "image/webp",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Body, Method};
+    use crate::router::Router;
+    use crate::temp_dir::TempDir;
+    use std::fs;
+    use std::io::Read;
+
+    // Each test gets its own directory, so they can run in parallel.
+
+    fn root(name: &str) -> TempDir {
+        let root = TempDir::new(&format!("static-files-{}", name));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+        root
+    }
+
+    fn get(root: &Path, target: &str) -> Response {
+        let router = Router::new().get("/static/*path", StaticFiles::new(root));
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        let request = Request::parse(raw.as_bytes()).unwrap().unwrap().0;
+        assert_eq!(request.method, Method::Get);
+        router.handle(request)
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match response.body {
+            Body::File(mut file) => {
+                file.read_to_end(&mut bytes).unwrap();
+            }
+            Body::Bytes(b) => bytes = b,
+            other => panic!("unexpected body {:?}", other),
+        }
+        bytes
+    }
+
+    #[test]
+    fn serves_files_with_their_content_type() {
+        let root = root("serves");
+
+        let response = get(&root, "/static/logo.png");
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Content-Type"), Some("image/png"));
+        assert_eq!(body(response), [0x89, b'P', b'N', b'G', 0, 0xff]);
+
+        let response = get(&root, "/static/docs/");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(body(response), b"<h1>docs</h1>");
+
+        assert_eq!(body(get(&root, "/static/")), b"<h1>home</h1>");
+        assert_eq!(body(get(&root, "/static/d%6fcs/")), b"<h1>docs</h1>");
+
+        // A directory's index is only served from a URL ending with a slash, like a listing.
+        let response = get(&root, "/static/docs?page=2");
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some("/static/docs/?page=2")
+        );
+        assert_eq!(
+            get(&root, "/static/missing.txt").status,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn serves_ranges() {
+        let root = root("ranges");
+        let get_range = |range: &str| {
+            let router = Router::new().get("/static/*path", StaticFiles::new(&root));
+            let raw = format!("GET /static/logo.png HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            router.handle(Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        let response = get_range("bytes=1-3");
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 1-3/6"));
+        assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+        match response.body {
+            Body::Sized(reader, length) => {
+                let mut bytes = Vec::new();
+                reader.take(length).read_to_end(&mut bytes).unwrap();
+                assert_eq!(bytes, b"PNG");
+            }
+            other => panic!("unexpected body {:?}", other),
+        }
+
+        let response = get_range("bytes=10-");
+        assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */6"));
+
+        assert_eq!(get_range("pages=1").status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let root = root("conditional");
+        let get_if = |headers: &str| {
+            let router = Router::new().get("/static/*path", StaticFiles::new(&root));
+            let raw = format!("GET /static/logo.png HTTP/1.1\r\n{}\r\n", headers);
+            router.handle(Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        let response = get_if("");
+        assert_eq!(response.status, StatusCode::Ok);
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let last_modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+        let response = get_if(&format!("If-None-Match: {}\r\n", etag));
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(response.body.len(), Some(0));
+
+        let response = get_if(&format!("If-Modified-Since: {}\r\n", last_modified));
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert_eq!(
+            get_if("If-None-Match: \"stale\"\r\n").status,
+            StatusCode::Ok
+        );
+
+        // A Range is only honoured if the file is the one the client already has part of.
+        let range = "Range: bytes=1-3\r\n";
+        let response = get_if(&format!("{}If-Range: {}\r\n", range, etag));
+        assert_eq!(response.status, StatusCode::PartialContent);
+        let response = get_if(&format!("{}If-Range: \"stale\"\r\n", range));
+        assert_eq!(response.status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn lists_directories_when_enabled() {
+        let root = root("listings");
+        fs::create_dir(root.join("files")).unwrap();
+        fs::write(root.join("files/notes.txt"), "hello").unwrap();
+        fs::write(root.join("files/.secret"), "hidden").unwrap();
+
+        let get_with = |listings: bool, target: &str, accept: &str| {
+            let files = StaticFiles::new(&root).listings(listings);
+            let router = Router::new().get("/static/*path", files);
+            let raw = format!("GET {} HTTP/1.1\r\nAccept: {}\r\n\r\n", target, accept);
+            router.handle(Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        assert_eq!(
+            get_with(false, "/static/files/", "*/*").status,
+            StatusCode::NotFound
+        );
+
+        let response = get_with(true, "/static/files/", "*/*");
+        assert_eq!(response.status, StatusCode::Ok);
+        let page = String::from_utf8(body(response)).unwrap();
+        assert!(page.contains("<a href=\"notes.txt\">notes.txt</a>"));
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(!page.contains("secret"));
+
+        let response = get_with(true, "/static/files/", "application/json");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        let json = String::from_utf8(body(response)).unwrap();
+        assert!(
+            json.starts_with("{\"path\":\"/static/files/\",\"entries\":[{\"name\":\"notes.txt\"")
+        );
+
+        let response = get_with(true, "/static/files?sort=size", "*/*");
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some("/static/files/?sort=size")
+        );
+
+        // Directories with an index still get the index.
+        assert_eq!(
+            body(get_with(true, "/static/docs/", "*/*")),
+            b"<h1>docs</h1>"
+        );
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let root = root("traversal");
+
+        for target in &[
+            "/static/../Cargo.toml",
+            "/static/docs/../../secret",
+            "/static/%2e%2e/secret",
+            "/static/%2E%2E%2Fsecret",
+            "/static/docs/..%5c..%5csecret",
+        ] {
+            assert_eq!(
+                get(&root, target).status,
+                StatusCode::Forbidden,
+                "{}",
+                target
+            );
+        }
+
+        assert_eq!(get(&root, "/static/%zz").status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(
+            content_type(Path::new("a/site.CSS")),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("photo.jpeg")), "image/jpeg");
+        assert_eq!(
+            content_type(Path::new("Makefile")),
+            "application/octet-stream"
+        );
+    }
+}
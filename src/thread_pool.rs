@@ -1,12 +1,403 @@
-use std::sync::{mpsc, Arc, Mutex};
+use crate::json::json_string;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::error;
+use std::fmt;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The lane used by `execute` and friends. It exists in every pool, doesn't reserve any workers,
+/// and has no queue limit unless it is configured explicitly with `ThreadPoolBuilder::lane`.
+pub const DEFAULT_LANE: &str = "default";
 
 enum WorkerMessage {
-    DoWork(Job),
+    DoWork(Task),
     Terminate,
 }
 
+/// Identifies a single job submitted to a ThreadPool. Ids are unique within a pool, and a job that
+/// gets retried keeps the same id for every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Describes the job a pool thread is running. See `current_job`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub label: Option<String>,
+    pub lane: String,
+}
+
+impl fmt::Display for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "job {} ({})", self.id, label),
+            None => write!(f, "job {}", self.id),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_JOB: RefCell<Option<JobInfo>> = const { RefCell::new(None) };
+}
+
+/// Returns the job that the calling thread is running, if it is a ThreadPool worker that is in
+/// the middle of running a job. This lets code deep inside a job tag its log lines without having
+/// the job id passed all the way down to it.
+pub fn current_job() -> Option<JobInfo> {
+    CURRENT_JOB.with(|current| current.borrow().clone())
+}
+
+/// How a job should be queued, for `ThreadPool::submit`.
+#[derive(Debug, Clone)]
+pub struct JobOptions {
+    lane: String,
+    label: Option<String>,
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        JobOptions::new()
+    }
+}
+
+impl JobOptions {
+    /// Unlabelled, on the default lane.
+    pub fn new() -> Self {
+        JobOptions {
+            lane: DEFAULT_LANE.to_string(),
+            label: None,
+        }
+    }
+
+    pub fn lane(mut self, lane: &str) -> Self {
+        self.lane = lane.to_string();
+        self
+    }
+
+    /// A human readable name for the job, such as the request line it is serving. It shows up in
+    /// the pool's log lines, its trace events and `current_job`.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+}
+
+struct Task {
+    info: JobInfo,
+    // Index into the pool's lanes.
+    lane: usize,
+    job: Job,
+}
+
+/// The error type that fallible jobs report through `ThreadPool::errors`.
+pub type JobError = Box<dyn error::Error + Send + Sync>;
+
+/// A job that failed, either by returning an error or by panicking.
+#[derive(Debug, Clone)]
+pub struct JobFailure {
+    pub job_id: JobId,
+    pub worker_id: usize,
+    /// When the failure was noticed by the worker, which is right after the job returned.
+    pub timestamp: SystemTime,
+    pub kind: FailureKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum FailureKind {
+    /// The job returned this error. It's wrapped in an Arc so that every subscriber gets a copy.
+    Error(Arc<dyn error::Error + Send + Sync>),
+    /// The job panicked with this message.
+    Panic(String),
+}
+
+impl fmt::Display for JobFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            FailureKind::Error(err) => write!(
+                f,
+                "job {} failed on worker {}: {}",
+                self.job_id, self.worker_id, err
+            ),
+            FailureKind::Panic(message) => write!(
+                f,
+                "job {} panicked on worker {}: {}",
+                self.job_id, self.worker_id, message
+            ),
+        }
+    }
+}
+
+/// Something that happened to a job, as reported by `ThreadPool::trace`.
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub job: JobInfo,
+    pub timestamp: SystemTime,
+    pub kind: TraceEventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEventKind {
+    /// The job was put on its lane's queue. A job that gets retried is queued again for every
+    /// attempt.
+    Submitted,
+    Started {
+        worker_id: usize,
+    },
+    /// The job returned (or panicked); `failed` is true unless it returned Ok.
+    Finished {
+        worker_id: usize,
+        failed: bool,
+    },
+}
+
+/// Formats trace events in the Chrome trace event format, which can be loaded into
+/// chrome://tracing or https://ui.perfetto.dev.
+///
+/// Every worker gets its own track showing the jobs it ran, and every job also gets an async span
+/// running from submission to completion, which shows how long it spent waiting in the queue.
+pub fn chrome_trace_json(events: &[TraceEvent]) -> String {
+    let mut json = String::from("{\"traceEvents\":[");
+
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        let micros = event
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros());
+        let name = json_string(&event.job.to_string());
+        let lane = json_string(&event.job.lane);
+        let id = event.job.id;
+
+        // Workers get tids starting at 1, leaving tid 0 for the async spans.
+
+        let _ = match event.kind {
+            TraceEventKind::Submitted => write!(
+                json,
+                "{{\"name\":{},\"cat\":{},\"ph\":\"b\",\"id\":{},\"ts\":{},\"pid\":0,\"tid\":0}}",
+                name, lane, id, micros
+            ),
+            TraceEventKind::Started { worker_id } => write!(
+                json,
+                "{{\"name\":{},\"cat\":{},\"ph\":\"B\",\"ts\":{},\"pid\":0,\"tid\":{}}}",
+                name,
+                lane,
+                micros,
+                worker_id + 1
+            ),
+            TraceEventKind::Finished { worker_id, failed } => write!(
+                json,
+                "{{\"name\":{},\"cat\":{},\"ph\":\"E\",\"ts\":{},\"pid\":0,\"tid\":{},\"args\":{{\"failed\":{}}}}},\
+                 {{\"name\":{},\"cat\":{},\"ph\":\"e\",\"id\":{},\"ts\":{},\"pid\":0,\"tid\":0}}",
+                name,
+                lane,
+                micros,
+                worker_id + 1,
+                failed,
+                name,
+                lane,
+                id,
+                micros
+            ),
+        };
+    }
+
+    json.push_str("]}");
+    json
+}
+
+/// Why a job couldn't be queued.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// No lane with this name was configured on the pool.
+    UnknownLane(String),
+    /// The lane already has as many jobs waiting as its queue limit allows.
+    QueueFull(String),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::UnknownLane(lane) => write!(f, "no lane named {:?}", lane),
+            ExecuteError::QueueFull(lane) => write!(f, "the queue for lane {:?} is full", lane),
+        }
+    }
+}
+
+impl error::Error for ExecuteError {}
+
+// A lane is a named queue with a number of workers reserved for it. Jobs in a lane can always use
+// the lane's reserved workers, and can borrow any workers that aren't reserved by any lane, but
+// never the workers reserved by another lane. That way a flood of slow jobs in one lane can fill up
+// its own queue and the unreserved workers, while every other lane keeps its share of the pool.
+
+struct Lane {
+    name: String,
+    min_workers: usize,
+    queue_limit: Option<usize>,
+    jobs: VecDeque<Task>,
+    // How many workers are currently running jobs from this lane.
+    running: usize,
+}
+
+struct Queue {
+    lanes: Vec<Lane>,
+    // Workers that aren't reserved by any lane.
+    unreserved: usize,
+    // The lane to look at first when picking the next job, so that busy lanes take turns.
+    next_lane: usize,
+    // Terminate messages that haven't been picked up by a worker yet.
+    terminate: usize,
+    // Workers whose thread hasn't finished yet.
+    live_workers: usize,
+}
+
+impl Queue {
+    fn lane(&self, name: &str) -> Result<usize, ExecuteError> {
+        self.lanes
+            .iter()
+            .position(|lane| lane.name == name)
+            .ok_or_else(|| ExecuteError::UnknownLane(name.to_string()))
+    }
+
+    // Takes the next job that is allowed to run, marking its lane as running one more job.
+
+    fn pop(&mut self) -> Option<Task> {
+        // A lane running more jobs than it has reserved workers is borrowing the difference from
+        // the unreserved ones.
+
+        let borrowed: usize = self
+            .lanes
+            .iter()
+            .map(|lane| lane.running.saturating_sub(lane.min_workers))
+            .sum();
+
+        let count = self.lanes.len();
+        for offset in 0..count {
+            let index = (self.next_lane + offset) % count;
+            let lane = &mut self.lanes[index];
+
+            if lane.jobs.is_empty() {
+                continue;
+            }
+            if lane.running < lane.min_workers || borrowed < self.unreserved {
+                lane.running += 1;
+                self.next_lane = (index + 1) % count;
+                return lane.jobs.pop_front();
+            }
+        }
+
+        None
+    }
+}
+
+// State that is shared by the pool handle and all of its workers.
+
+struct Shared {
+    next_job_id: AtomicU64,
+    failure_subscribers: Mutex<Vec<mpsc::Sender<JobFailure>>>,
+    trace_subscribers: Mutex<Vec<mpsc::Sender<TraceEvent>>>,
+    queue: Mutex<Queue>,
+    // Signalled whenever a job or a Terminate message is added to the queue.
+    available: Condvar,
+    // Signalled whenever a worker thread is about to exit.
+    exited: Condvar,
+}
+
+impl Shared {
+    fn next_job_id(&self) -> JobId {
+        JobId(self.next_job_id.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+
+    fn report(&self, failure: JobFailure) {
+        println!("{}", failure);
+
+        // Send a copy to every subscriber, forgetting about the ones whose receiver has been
+        // dropped.
+
+        self.failure_subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(failure.clone()).is_ok());
+    }
+
+    fn trace(&self, job: &JobInfo, kind: TraceEventKind) {
+        let mut subscribers = self.trace_subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let event = TraceEvent {
+            job: job.clone(),
+            timestamp: SystemTime::now(),
+            kind,
+        };
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    // Puts a job back on its lane's queue, regardless of the lane's queue limit: the job was
+    // already accepted once, so turning it away now would only lose it.
+
+    fn requeue(&self, task: Task) {
+        let mut queue = self.queue.lock().unwrap();
+        self.trace(&task.info, TraceEventKind::Submitted);
+        queue.lanes[task.lane].jobs.push_back(task);
+        self.available.notify_one();
+    }
+
+    // Blocks until there is either a job that is allowed to run or a Terminate message. Jobs come
+    // first, so that everything that was queued before the pool was dropped still gets to run.
+
+    fn next_message(&self) -> WorkerMessage {
+        // Here, we first call lock on the queue to acquire the mutex, and then we call unwrap to
+        // panic on any errors. Acquiring a lock might fail if the mutex is in a poisoned state,
+        // which can happen if some other thread panicked while holding the lock rather than
+        // releasing the lock. In this situation, calling unwrap to have this thread panic is the
+        // correct action to take.
+
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if let Some(task) = queue.pop() {
+                return WorkerMessage::DoWork(task);
+            }
+            if queue.terminate > 0 {
+                queue.terminate -= 1;
+                return WorkerMessage::Terminate;
+            }
+
+            // Waiting on the condition variable releases the lock until another thread notifies
+            // us, so the other workers (and the pool) can get at the queue in the meantime.
+
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+
+    fn finish(&self, lane: usize) {
+        // The worker that ran the job goes straight back to next_message, so there's no need to
+        // wake anybody else up to use the worker we just freed. This can run while the worker is
+        // dying of a panic, in which case the lock may be poisoned, but the count is still right.
+
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.lanes[lane].running -= 1;
+    }
+}
+
 // Each Worker stores a single JoinHandle<()> instance. Each worker has an id so we can distinguish
 // between the different workers in the pool when logging or debugging.
 
@@ -16,44 +407,65 @@ struct Worker {
 }
 
 impl Worker {
-    // Using an Arc<Mutex>> because taking a job off the channel queue involves
-    // mutating the receiver, so the threads need a safe way to share and modify
-    // receiver; otherwise, we might get race conditions.
+    // Using an Arc because every worker takes jobs off the same set of queues. The queues live
+    // behind a Mutex, because taking a job off a queue involves mutating it, so the threads need a
+    // safe way to share and modify them; otherwise, we might get race conditions.
 
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<WorkerMessage>>>) -> Self {
+    fn new(id: usize, shared: Arc<Shared>) -> Self {
         let thread = thread::spawn(move || {
+            // The worker is counted out when the guard is dropped, however its thread ends. A
+            // thread that died without counting itself out would keep a dropped pool waiting for
+            // the whole of its timeout.
+
+            let _live = LiveWorker(Arc::clone(&shared));
             loop {
-                // Here, we first call lock on the receiver to acquire the mutex, and then we
-                // call unwrap to panic on any errors. Acquiring a lock might fail if the mutex
-                // is in a poisoned state, which can happen if some other thread panicked while
-                // holding the lock rather than releasing the lock. In this situation, calling
-                // unwrap to have this thread panic is the correct action to take.
-
-                // Note that .lock acquires a mutex, blocking the current thread until it is
-                // able to do so.
-
-                // If we get the lock on the mutex, we call recv to receive a message from the
-                // channel. A final unwrap moves past any errors here as well, which might occur if
-                // the thread holding the sending side of the channel has shut down, similar to how
-                // the send method returns Err if the receiving side shuts down.
-
-                // The call to recv blocks, so if there is no message yet, the current thread will
-                // wait until a message becomes available. The Mutex<T> ensures that only one Worker
-                // thread at a time is trying to request a message.
-
-                match receiver.lock().unwrap().recv().unwrap() {
-                    WorkerMessage::DoWork(job) => {
-                        // Note that the temporary MutexGuard returned from the lock method is dropped
-                        // as soon as the "let job =" statement ends. This ensures that the lock is held
-                        // during the call to recv, but it is released before the call to job(),
-                        // allowing multiple requests to be serviced concurrently.
-
-                        // AKA, we are freeing up other threads to access the receiver *before* the job
-                        // is run.
-
-                        println!("thread {} received a new job.", id);
-                        job();
-                        println!("thread {} job finished.", id);
+                match shared.next_message() {
+                    WorkerMessage::DoWork(task) => {
+                        // Note that the lock on the queue is released as soon as next_message
+                        // returns, before the job is run, allowing multiple requests to be
+                        // serviced concurrently.
+
+                        println!("thread {} received {}.", id, task.info);
+                        shared.trace(&task.info, TraceEventKind::Started { worker_id: id });
+                        CURRENT_JOB.with(|current| *current.borrow_mut() = Some(task.info.clone()));
+
+                        // A panicking job used to take its worker down with it, leaving the pool
+                        // one thread short for the rest of its life. Catching the panic keeps the
+                        // worker alive, and lets us report the panic like any other failure.
+
+                        // The lane gets its worker back when the guard is dropped, even if the
+                        // worker dies on the way, say because dropping the panic's payload
+                        // panicked too. Otherwise the lane would count it as busy forever.
+
+                        let running = RunningJob {
+                            shared: &shared,
+                            lane: task.lane,
+                        };
+                        let kind = match panic::catch_unwind(AssertUnwindSafe(task.job)) {
+                            Ok(Ok(())) => None,
+                            Ok(Err(err)) => Some(FailureKind::Error(Arc::from(err))),
+                            Err(payload) => Some(FailureKind::Panic(panic_message(payload))),
+                        };
+                        CURRENT_JOB.with(|current| *current.borrow_mut() = None);
+                        drop(running);
+                        shared.trace(
+                            &task.info,
+                            TraceEventKind::Finished {
+                                worker_id: id,
+                                failed: kind.is_some(),
+                            },
+                        );
+
+                        if let Some(kind) = kind {
+                            shared.report(JobFailure {
+                                job_id: task.info.id,
+                                worker_id: id,
+                                timestamp: SystemTime::now(),
+                                kind,
+                            });
+                        }
+
+                        println!("thread {} {} finished.", id, task.info);
                     }
                     WorkerMessage::Terminate => {
                         println!("worker is terminating");
@@ -69,44 +481,169 @@ impl Worker {
     }
 }
 
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<WorkerMessage>,
+// Held by a worker's thread for as long as it runs.
+
+struct LiveWorker(Arc<Shared>);
+
+impl Drop for LiveWorker {
+    fn drop(&mut self) {
+        // If the thread is dying because it panicked while holding the lock, the lock is
+        // poisoned, but the count in it is still right.
+
+        let mut queue = self.0.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.live_workers -= 1;
+        drop(queue);
+        self.0.exited.notify_all();
+    }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+// Held by a worker's thread while it runs a job from `lane`.
 
-impl ThreadPool {
-    pub fn new(size: usize) -> Self {
+struct RunningJob<'a> {
+    shared: &'a Shared,
+    lane: usize,
+}
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        self.shared.finish(self.lane);
+    }
+}
+
+// A panic payload is whatever was passed to panic!, which is almost always a &str or a String.
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
+
+/// What dropping a ThreadPool does about its workers. Whatever the policy, the workers are told to
+/// terminate once they've run every job that is already queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Block until every worker has finished. This is the default.
+    JoinAll,
+    /// Block until every worker has finished, or until the timeout runs out, whichever comes
+    /// first. Workers that are still busy after the timeout are detached.
+    JoinWithTimeout(Duration),
+    /// Don't wait for anything. The workers keep running in the background until they have
+    /// drained the queue, or until the process exits.
+    Detach,
+}
+
+/// Configures a ThreadPool before any of its threads are started. `ThreadPool::new(size)` is the
+/// same as `ThreadPool::builder(size).build()`.
+pub struct ThreadPoolBuilder {
+    size: usize,
+    lanes: Vec<Lane>,
+    drop_policy: DropPolicy,
+}
+
+impl ThreadPoolBuilder {
+    /// Adds a lane that always has `min_workers` workers to itself, and accepts at most
+    /// `queue_limit` waiting jobs (or any number of them, with None). Configuring a lane that
+    /// already exists, including `DEFAULT_LANE`, replaces its settings.
+    pub fn lane(mut self, name: &str, min_workers: usize, queue_limit: Option<usize>) -> Self {
+        let lane = Lane {
+            name: name.to_string(),
+            min_workers,
+            queue_limit,
+            jobs: VecDeque::new(),
+            running: 0,
+        };
+
+        match self.lanes.iter_mut().find(|existing| existing.name == name) {
+            Some(existing) => *existing = lane,
+            None => self.lanes.push(lane),
+        }
+        self
+    }
+
+    pub fn drop_policy(mut self, drop_policy: DropPolicy) -> Self {
+        self.drop_policy = drop_policy;
+        self
+    }
+
+    pub fn build(self) -> ThreadPool {
+        let size = self.size;
         assert!(size > 0);
 
-        let mut workers = Vec::with_capacity(size);
-        let (sender, receiver) = mpsc::channel();
+        let reserved: usize = self.lanes.iter().map(|lane| lane.min_workers).sum();
+        assert!(
+            reserved <= size,
+            "lanes reserve {} workers, but the pool only has {}",
+            reserved,
+            size
+        );
 
-        // the channel implementation that Rust provides is multiple producer, single consumer. This
-        // means we can’t just clone the consuming end of the channel to fix this code. Even if we
-        // could, that is not the technique we would want to use; instead, we want to distribute the
-        // jobs across threads by sharing the single receiver among all the workers.
+        let mut workers = Vec::with_capacity(size);
 
-        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            next_job_id: AtomicU64::new(0),
+            failure_subscribers: Mutex::new(Vec::new()),
+            trace_subscribers: Mutex::new(Vec::new()),
+            queue: Mutex::new(Queue {
+                lanes: self.lanes,
+                unreserved: size - reserved,
+                next_lane: 0,
+                terminate: 0,
+                live_workers: size,
+            }),
+            available: Condvar::new(),
+            exited: Condvar::new(),
+        });
 
         for i in 0..size {
             // create some threads and store them in the vector
 
             // For each new worker, we clone the Arc to bump the reference count so the workers can
-            // share ownership of the receiving end.
+            // share ownership of the queues.
 
-            workers.push(Worker::new(i, Arc::clone(&receiver)));
-            // workers.push(Worker::new(i, receiver.clone()));
+            workers.push(Worker::new(i, Arc::clone(&shared)));
 
             println!("new worker has been started with id: {}", i);
         }
 
-        ThreadPool { workers, sender }
+        let scheduler = Scheduler::new(Arc::clone(&shared));
+
+        ThreadPool {
+            workers,
+            scheduler,
+            shared,
+            drop_policy: self.drop_policy,
+        }
     }
+}
 
-    /// Takes a closure of code to run and sends it to the already running thread for execution.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    scheduler: Scheduler,
+    shared: Arc<Shared>,
+    drop_policy: DropPolicy,
+}
+
+type Job = Box<dyn FnOnce() -> Result<(), JobError> + Send + 'static>;
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        ThreadPool::builder(size).build()
+    }
 
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            lanes: Vec::new(),
+            drop_policy: DropPolicy::JoinAll,
+        }
+        .lane(DEFAULT_LANE, 0, None)
+    }
+
+    /// Takes a closure of code to run and sends it to the already running thread for execution.
     pub fn execute<F>(&self, job: F)
     where
         // The type for F is taken from the method signature of thread::spawn() here:
@@ -114,51 +651,867 @@ impl ThreadPool {
         F: FnOnce(),
         F: Send + 'static,
     {
-        // Send the job down the sending end of the channel.
+        self.execute_in(DEFAULT_LANE, job)
+            // We’re calling expect for the case that the default lane is full. This can only happen
+            // if the default lane was given a queue limit, in which case the caller should be using
+            // execute_in to find out about it.
+            .expect("Failed to queue job on the default lane");
+    }
+
+    /// Like `execute`, but queues the job on the named lane. Fails if there is no such lane, or if
+    /// the lane's queue is full.
+    pub fn execute_in<F>(&self, lane: &str, job: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce(),
+        F: Send + 'static,
+    {
+        self.submit(
+            JobOptions::new().lane(lane),
+            move || -> Result<(), JobError> {
+                job();
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Like `execute`, but for jobs that can fail. Errors returned by the job, as well as panics,
+    /// are reported to every receiver handed out by `errors`. Returns the id the job will be
+    /// reported under.
+    pub fn execute_fallible<F, E>(&self, job: F) -> JobId
+    where
+        F: FnOnce() -> Result<(), E>,
+        F: Send + 'static,
+        E: Into<JobError>,
+    {
+        self.submit(JobOptions::new(), job)
+            .expect("Failed to queue job on the default lane")
+    }
+
+    /// The most general way of queueing a job: any of the lane, label and failure reporting can be
+    /// used together. Returns the id of the queued job.
+    pub fn submit<F, E>(&self, options: JobOptions, job: F) -> Result<JobId, ExecuteError>
+    where
+        F: FnOnce() -> Result<(), E>,
+        F: Send + 'static,
+        E: Into<JobError>,
+    {
+        self.send_with(options, |_, _| Box::new(move || job().map_err(Into::into)))
+    }
+
+    /// Returns a receiver for every job failure from now on. Each call returns a new receiver, and
+    /// each receiver gets its own copy of every failure, so that e.g. a supervisor and a logger
+    /// can both listen in. Failures that happened before the call are not replayed.
+    pub fn errors(&self) -> mpsc::Receiver<JobFailure> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.failure_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a receiver for the trace events of every job from now on. Like `errors`, every call
+    /// returns a new receiver that gets its own copy of each event. See `chrome_trace_json` for a
+    /// way to look at them.
+    pub fn trace(&self) -> mpsc::Receiver<TraceEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.trace_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Queues the job built by make_job. Some jobs need to know their own id and lane, which are
+    // only settled once we hold the queue lock, so they get passed in.
+
+    fn send_with<M>(&self, options: JobOptions, make_job: M) -> Result<JobId, ExecuteError>
+    where
+        M: FnOnce(&JobInfo, usize) -> Job,
+    {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        let index = queue.lane(&options.lane)?;
+        let lane = &mut queue.lanes[index];
+        if lane
+            .queue_limit
+            .is_some_and(|limit| lane.jobs.len() >= limit)
+        {
+            return Err(ExecuteError::QueueFull(lane.name.clone()));
+        }
+
+        let info = JobInfo {
+            id: self.shared.next_job_id(),
+            label: options.label,
+            lane: options.lane,
+        };
+        let id = info.id;
+
+        // Tracing while we still hold the lock guarantees that nobody sees the job start before
+        // they've seen it being submitted.
+
+        self.shared.trace(&info, TraceEventKind::Submitted);
+        lane.jobs.push_back(Task {
+            job: make_job(&info, index),
+            info,
+            lane: index,
+        });
+
+        // Every idle worker is as good as any other, so waking one of them up is enough. If the
+        // lane can't run another job right now, the worker goes straight back to sleep, and the
+        // job is picked up by whichever worker frees up a slot for it.
+
+        self.shared.available.notify_one();
+
+        Ok(id)
+    }
+
+    /// Runs a fallible closure on the pool, retrying it according to `policy` until it succeeds,
+    /// returns an error that the policy doesn't consider retryable, or runs out of attempts.
+    ///
+    /// Retries don't sleep inside the worker: a failed attempt hands the closure over to the
+    /// pool's scheduler thread, which puts it back on the job queue once the backoff has elapsed.
+    /// That way a job that is waiting to be retried never holds on to a worker.
+    pub fn submit_retrying<T, E, F>(&self, policy: RetryPolicy<E>, job: F) -> JobHandle<T, E>
+    where
+        F: FnMut() -> Result<T, E>,
+        F: Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let (outcomes, receiver) = mpsc::channel();
+
+        let scheduler = self.scheduler.sender.clone();
+        self.send_with(JobOptions::new(), move |info, lane| {
+            let attempt = RetryingJob {
+                info: info.clone(),
+                lane,
+                policy,
+                job,
+                attempt: 1,
+                seed: seed(),
+                outcomes,
+                scheduler,
+            };
+            Box::new(move || attempt.run())
+        })
+        .expect("Failed to queue job on the default lane");
+
+        JobHandle { receiver }
+    }
+}
+
+// Delayed jobs are handed to a single scheduler thread, which keeps them in a min-heap ordered by
+// deadline and moves each one back onto its lane's queue once it is due. The scheduler only ever
+// waits on its own channel, so it never competes with the workers for jobs.
+
+enum SchedulerMessage {
+    Schedule(DelayedJob),
+    Terminate,
+}
+
+struct DelayedJob {
+    due: Instant,
+    task: Task,
+}
+
+// BinaryHeap is a max-heap, so the ordering is reversed to pop the earliest deadline first.
+
+impl Ord for DelayedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.due.cmp(&self.due)
+    }
+}
+
+impl PartialOrd for DelayedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for DelayedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due
+    }
+}
+
+impl Eq for DelayedJob {}
+
+struct Scheduler {
+    sender: mpsc::Sender<SchedulerMessage>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    fn new(shared: Arc<Shared>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let mut pending: BinaryHeap<DelayedJob> = BinaryHeap::new();
+
+            loop {
+                let now = Instant::now();
+                while pending.peek().is_some_and(|next| next.due <= now) {
+                    shared.requeue(pending.pop().unwrap().task);
+                }
+
+                let message = match pending.peek() {
+                    Some(next) => match receiver.recv_timeout(next.due - now) {
+                        Ok(message) => message,
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match receiver.recv() {
+                        Ok(message) => message,
+                        Err(_) => break,
+                    },
+                };
+
+                match message {
+                    SchedulerMessage::Schedule(delayed) => pending.push(delayed),
+                    SchedulerMessage::Terminate => break,
+                }
+            }
+
+            // Anything still pending is dropped here, which disconnects the JobHandles waiting on
+            // those jobs.
+        });
+
+        Scheduler {
+            sender,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// How long to wait between two attempts of a job submitted with `ThreadPool::submit_retrying`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same amount of time before every retry.
+    Fixed(Duration),
+    /// Double the wait after every failed attempt, starting at `initial` and never exceeding `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+/// Describes how often, and how eagerly, a fallible job should be retried. Policies are built up
+/// from `RetryPolicy::new(max_attempts)` with the `backoff`, `jitter` and `retry_if` methods.
+pub struct RetryPolicy<E> {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: bool,
+    retryable: Box<dyn Fn(&E) -> bool + Send>,
+}
+
+impl<E> RetryPolicy<E> {
+    /// Creates a policy that runs a job at most `max_attempts` times (including the first attempt),
+    /// retrying immediately on every error.
+    pub fn new(max_attempts: u32) -> Self {
+        assert!(max_attempts > 0);
+
+        RetryPolicy {
+            max_attempts,
+            backoff: Backoff::Fixed(Duration::from_secs(0)),
+            jitter: false,
+            retryable: Box::new(|_| true),
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// With jitter enabled, each wait is picked uniformly between zero and the backoff delay, so
+    /// that many jobs failing at once don't all retry at the same moment.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only errors for which `predicate` returns true are retried; any other error fails the job
+    /// straight away.
+    pub fn retry_if<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&E) -> bool,
+        P: Send + 'static,
+    {
+        self.retryable = Box::new(predicate);
+        self
+    }
+
+    /// The delay before the next attempt, after `attempt` attempts have failed, without jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                initial
+                    .checked_mul(factor)
+                    .map_or(max, |delay| delay.min(max))
+            }
+        }
+    }
+}
+
+/// Why a job submitted with `ThreadPool::submit_retrying` gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryFailure {
+    /// Every attempt allowed by the policy failed.
+    Exhausted,
+    /// The last error was not retryable according to the policy.
+    NotRetryable,
+    /// The job was dropped before it could finish, either because it panicked or because the pool
+    /// was dropped while a retry was still waiting for its backoff.
+    Cancelled,
+}
 
-        self.sender
-            .send(WorkerMessage::DoWork(Box::new(job)))
-            // We’re calling expect on send for the case that sending fails. This might happen if,
-            // for example, we stop all our threads from executing, meaning the receiving end has
-            // stopped receiving new messages. Currently, we can’t stop our threads from executing:
-            // our threads continue executing as long as the pool exists. The reason we use unwrap
-            // is that we know the failure case won’t happen, but the compiler doesn’t know that.
-            .expect("Failed to send job to channel consumer");
+/// The errors of every failed attempt, oldest first, along with the reason we stopped retrying.
+#[derive(Debug)]
+pub struct RetryError<E> {
+    pub errors: Vec<E>,
+    pub failure: RetryFailure,
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.failure {
+            RetryFailure::Exhausted => "gave up after running out of attempts",
+            RetryFailure::NotRetryable => "gave up on a non-retryable error",
+            RetryFailure::Cancelled => "was cancelled",
+        };
+        write!(f, "job {} ({} failed attempts)", reason, self.errors.len())?;
+        if let Some(last) = self.errors.last() {
+            write!(f, ": {}", last)?;
+        }
+        Ok(())
     }
 }
 
-/// When the pool is dropped, our threads should all join to make sure they finish their work.
+impl<E: fmt::Debug + fmt::Display> error::Error for RetryError<E> {}
+
+/// A handle to the result of a job submitted with `ThreadPool::submit_retrying`.
+pub struct JobHandle<T, E> {
+    receiver: mpsc::Receiver<Outcome<T, E>>,
+}
+
+enum Outcome<T, E> {
+    Failed(E),
+    GaveUp(RetryFailure),
+    Succeeded(T),
+}
+
+impl<T, E> JobHandle<T, E> {
+    /// Blocks until the job has either succeeded or given up.
+    pub fn join(self) -> Result<T, RetryError<E>> {
+        let mut errors = Vec::new();
+
+        // Each failed attempt reports its error as soon as it happens, so even a job that gets
+        // cancelled half way through still tells us about the attempts it did make.
+
+        loop {
+            match self.receiver.recv() {
+                Ok(Outcome::Failed(err)) => errors.push(err),
+                Ok(Outcome::Succeeded(value)) => return Ok(value),
+                Ok(Outcome::GaveUp(failure)) => return Err(RetryError { errors, failure }),
+                Err(mpsc::RecvError) => {
+                    return Err(RetryError {
+                        errors,
+                        failure: RetryFailure::Cancelled,
+                    })
+                }
+            }
+        }
+    }
+}
+
+struct RetryingJob<T, E, F> {
+    info: JobInfo,
+    lane: usize,
+    policy: RetryPolicy<E>,
+    job: F,
+    attempt: u32,
+    seed: u64,
+    outcomes: mpsc::Sender<Outcome<T, E>>,
+    scheduler: mpsc::Sender<SchedulerMessage>,
+}
+
+impl<T, E, F> RetryingJob<T, E, F>
+where
+    F: FnMut() -> Result<T, E>,
+    F: Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    // Failures are reported through the JobHandle rather than ThreadPool::errors, since the caller
+    // is already waiting on the handle for them.
+
+    fn run(mut self) -> Result<(), JobError> {
+        let err = match (self.job)() {
+            Ok(value) => {
+                let _ = self.outcomes.send(Outcome::Succeeded(value));
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        let retryable = (self.policy.retryable)(&err);
+        let _ = self.outcomes.send(Outcome::Failed(err));
+
+        if !retryable {
+            let _ = self
+                .outcomes
+                .send(Outcome::GaveUp(RetryFailure::NotRetryable));
+            return Ok(());
+        }
+        if self.attempt >= self.policy.max_attempts {
+            let _ = self.outcomes.send(Outcome::GaveUp(RetryFailure::Exhausted));
+            return Ok(());
+        }
+
+        let mut delay = self.policy.delay(self.attempt);
+        if self.policy.jitter {
+            delay = delay.mul_f64(next_random(&mut self.seed));
+        }
+        self.attempt += 1;
+
+        // If the scheduler has already shut down, the job is dropped along with the message, and
+        // the handle reports it as cancelled.
+
+        let scheduler = self.scheduler.clone();
+        let (info, lane) = (self.info.clone(), self.lane);
+        let _ = scheduler.send(SchedulerMessage::Schedule(DelayedJob {
+            due: Instant::now() + delay,
+            task: Task {
+                info,
+                lane,
+                job: Box::new(move || self.run()),
+            },
+        }));
+
+        Ok(())
+    }
+}
+
+// We don't have a random number crate, and jitter doesn't need to be anything more than
+// "different for each job", so a xorshift generator seeded from the clock is plenty.
+
+fn seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    // xorshift gets stuck on zero, so make sure we never start there.
+    nanos | 1
+}
+
+/// Returns a number in [0, 1).
+fn next_random(state: &mut u64) -> f64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// When the pool is dropped, our threads should all join to make sure they finish their work,
+/// unless the pool was built with a DropPolicy that says otherwise.
 ///
 /// When the ThreadPool goes out of scope at the end of main, its Drop implementation kicks in, and
 /// the pool tells all workers to terminate. The workers each print a message when they see the
 /// terminate message, and then the thread pool calls join to shut down each worker thread.
-
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         println!("drop trait!");
 
+        // Stop the scheduler first, so that it can't hand out any more delayed jobs once the
+        // workers have been told to terminate.
+
+        let _ = self.scheduler.sender.send(SchedulerMessage::Terminate);
+        if let Some(thread) = self.scheduler.thread.take() {
+            thread.join().unwrap();
+        }
+
         // We’re now iterating over the workers twice: once to send one Terminate message for each
         // worker and once to call join on each worker’s thread. If we tried to send a message and
         // join immediately in the same loop, we couldn’t guarantee that the worker in the current
-        // iteration would be the one to get the message from the channel.
+        // iteration would be the one to get the message from the queue.
 
         // So, we can be sure that if we send the same number of terminate messages as there are
         // workers, each worker will receive a terminate message before join is called on its
         // thread.
 
-        self.workers.iter().for_each(|_| {
-            self.sender
-                .send(WorkerMessage::Terminate)
-                .expect("Failed to send job to channel consumer")
-        });
+        self.shared.queue.lock().unwrap().terminate += self.workers.len();
+        self.shared.available.notify_all();
+
+        match self.drop_policy {
+            DropPolicy::JoinAll => {}
+            DropPolicy::Detach => {
+                println!("Detaching {} workers", self.workers.len());
+                return;
+            }
+            DropPolicy::JoinWithTimeout(timeout) => {
+                let queue = self.shared.queue.lock().unwrap();
+                let (queue, wait) = self
+                    .shared
+                    .exited
+                    .wait_timeout_while(queue, timeout, |queue| queue.live_workers > 0)
+                    .unwrap();
+                drop(queue);
+
+                if wait.timed_out() {
+                    // Dropping a JoinHandle detaches its thread, so all we need to do is forget
+                    // about the workers that haven't finished yet. The ones that have can still be
+                    // joined below, which returns immediately.
+
+                    for worker in &mut self.workers {
+                        if worker
+                            .thread
+                            .as_ref()
+                            .is_some_and(|thread| !thread.is_finished())
+                        {
+                            println!("Detaching worker {}", worker.id);
+                            worker.thread.take();
+                        }
+                    }
+                }
+            }
+        }
 
         for worker in &mut self.workers {
             println!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                // Block the main thread, and wait for the associated thread to finish.
-                thread.join().unwrap();
+                // Block the main thread, and wait for the associated thread to finish. A worker
+                // that died has already said why on stderr, so there's nothing more to do.
+                let _ = thread.join();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn retries_until_success() {
+        let pool = ThreadPool::new(2);
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&calls);
+        let handle = pool.submit_retrying(RetryPolicy::new(5), move || {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err("attempt failed"),
+                n => Ok(n),
+            }
+        });
+
+        assert_eq!(handle.join().unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn reports_every_error_when_exhausted() {
+        let pool = ThreadPool::new(1);
+        let mut attempt = 0;
+
+        let handle = pool.submit_retrying(RetryPolicy::new(3), move || -> Result<(), u32> {
+            attempt += 1;
+            Err(attempt)
+        });
+
+        let err = handle.join().unwrap_err();
+        assert_eq!(err.errors, vec![1, 2, 3]);
+        assert_eq!(err.failure, RetryFailure::Exhausted);
+    }
+
+    #[test]
+    fn stops_on_non_retryable_error() {
+        let pool = ThreadPool::new(1);
+        let mut errors = vec!["fatal", "transient"];
+
+        let policy = RetryPolicy::new(10).retry_if(|err: &&str| *err == "transient");
+        let handle = pool.submit_retrying(policy, move || -> Result<(), &str> {
+            Err(errors.pop().unwrap())
+        });
+
+        let err = handle.join().unwrap_err();
+        assert_eq!(err.errors, vec!["transient", "fatal"]);
+        assert_eq!(err.failure, RetryFailure::NotRetryable);
+    }
+
+    #[test]
+    fn backoff_does_not_block_the_worker() {
+        // With a single worker, a job submitted after the failing one can only finish first if the
+        // retry is waiting on the scheduler rather than sleeping in the worker.
+
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        let retry_sender = sender.clone();
+        let mut failed = false;
+        let policy = RetryPolicy::new(2).backoff(Backoff::Fixed(Duration::from_millis(200)));
+        let handle = pool.submit_retrying(policy, move || {
+            if !failed {
+                failed = true;
+                return Err(());
+            }
+            retry_sender.send("retry").unwrap();
+            Ok(())
+        });
+        pool.execute(move || sender.send("other").unwrap());
+
+        assert_eq!(receiver.recv().unwrap(), "other");
+        assert_eq!(receiver.recv().unwrap(), "retry");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn reports_errors_and_panics() {
+        let pool = ThreadPool::new(1);
+        let errors = pool.errors();
+
+        let failed = pool.execute_fallible(|| Err("no such file"));
+        let succeeded = pool.execute_fallible(|| -> Result<(), JobError> { Ok(()) });
+        pool.execute(|| panic!("boom"));
+
+        let failure = errors.recv().unwrap();
+        assert_eq!(failure.job_id, failed);
+        assert_eq!(failure.worker_id, 0);
+        assert!(
+            matches!(failure.kind, FailureKind::Error(ref err) if err.to_string() == "no such file")
+        );
+
+        // The panic didn't kill our only worker, and the successful job wasn't reported.
+        let failure = errors.recv().unwrap();
+        assert_ne!(failure.job_id, succeeded);
+        assert!(matches!(failure.kind, FailureKind::Panic(ref message) if message == "boom"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn busy_lane_cannot_starve_reserved_workers() {
+        // Two workers, one of which is reserved for the "admin" lane. Blocking the "api" lane can
+        // only ever tie up the unreserved worker.
+
+        let pool = ThreadPool::builder(2)
+            .lane("api", 0, Some(1))
+            .lane("admin", 1, None)
+            .build();
+
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        let (started, api_started) = mpsc::channel();
+        let api_job = move || {
+            let blocked = Arc::clone(&blocked);
+            let started = started.clone();
+            move || {
+                started.send(()).unwrap();
+                blocked.lock().unwrap().recv().unwrap();
+            }
+        };
+
+        // The first api job is running, and the second one fills the api queue.
+        pool.execute_in("api", api_job()).unwrap();
+        api_started.recv().unwrap();
+        pool.execute_in("api", api_job()).unwrap();
+        assert_eq!(
+            pool.execute_in("api", || {}),
+            Err(ExecuteError::QueueFull(String::from("api")))
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute_in("admin", move || sender.send("admin").unwrap())
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "admin"
+        );
+
+        // The second api job is still waiting for the unreserved worker.
+        assert!(api_started.try_recv().is_err());
+
+        release.send(()).unwrap();
+        api_started.recv_timeout(Duration::from_secs(5)).unwrap();
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn unknown_lane_is_an_error() {
+        let pool = ThreadPool::new(1);
+
+        assert_eq!(
+            pool.execute_in("static-files", || {}),
+            Err(ExecuteError::UnknownLane(String::from("static-files")))
+        );
+    }
+
+    #[test]
+    fn jobs_can_see_their_own_id_and_label() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        let options = JobOptions::new().label("GET /sleep");
+        let id = pool
+            .submit(options, move || -> Result<(), JobError> {
+                sender.send(current_job()).unwrap();
+                Ok(())
+            })
+            .unwrap();
+
+        let info = receiver.recv().unwrap().unwrap();
+        assert_eq!(info.id, id);
+        assert_eq!(info.label.as_deref(), Some("GET /sleep"));
+        assert_eq!(info.lane, DEFAULT_LANE);
+
+        // Outside of a job there is nothing to report.
+        assert_eq!(current_job(), None);
+    }
+
+    #[test]
+    fn traces_the_lifecycle_of_a_job() {
+        let pool = ThreadPool::new(1);
+        let trace = pool.trace();
+
+        pool.submit(JobOptions::new().label("ok"), || -> Result<(), JobError> {
+            Ok(())
+        })
+        .unwrap();
+        pool.submit(JobOptions::new().label("fails"), || Err("nope"))
+            .unwrap();
+
+        let events: Vec<TraceEvent> = trace.iter().take(6).collect();
+        let kinds: Vec<(&str, TraceEventKind)> = events
+            .iter()
+            .map(|event| (event.job.label.as_deref().unwrap(), event.kind))
+            .filter(|(_, kind)| *kind != TraceEventKind::Submitted)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("ok", TraceEventKind::Started { worker_id: 0 }),
+                (
+                    "ok",
+                    TraceEventKind::Finished {
+                        worker_id: 0,
+                        failed: false
+                    }
+                ),
+                ("fails", TraceEventKind::Started { worker_id: 0 }),
+                (
+                    "fails",
+                    TraceEventKind::Finished {
+                        worker_id: 0,
+                        failed: true
+                    }
+                ),
+            ]
+        );
+
+        let json = chrome_trace_json(&events);
+        assert!(json.starts_with("{\"traceEvents\":[{\"name\":\"job 0 (ok)\""));
+        assert_eq!(json.matches("\"ph\":\"B\"").count(), 2);
+        assert_eq!(json.matches("\"ph\":\"e\"").count(), 2);
+    }
+
+    #[test]
+    fn drop_policies_decide_how_long_drop_blocks() {
+        for &policy in &[
+            DropPolicy::Detach,
+            DropPolicy::JoinWithTimeout(Duration::from_millis(100)),
+        ] {
+            let pool = ThreadPool::builder(1).drop_policy(policy).build();
+            let (release, blocked) = mpsc::channel::<()>();
+            let (sender, started) = mpsc::channel();
+            pool.execute(move || {
+                sender.send(()).unwrap();
+                let _ = blocked.recv();
+            });
+            started.recv().unwrap();
+
+            let dropping = Instant::now();
+            drop(pool);
+            assert!(dropping.elapsed() < Duration::from_secs(5));
+
+            // Let the detached worker finish, rather than leaving it blocked forever.
+            release.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn join_with_timeout_waits_for_quick_jobs() {
+        let pool = ThreadPool::builder(2)
+            .drop_policy(DropPolicy::JoinWithTimeout(Duration::from_secs(5)))
+            .build();
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            let finished = Arc::clone(&finished);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn join_with_timeout_counts_workers_that_died() {
+        // The job's panic is caught, but dropping its payload panics again, outside the catch,
+        // which takes the worker down.
+        struct PanicOnDrop;
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                panic!("dropped");
+            }
+        }
+
+        let pool = ThreadPool::builder(1)
+            .drop_policy(DropPolicy::JoinWithTimeout(Duration::from_secs(5)))
+            .build();
+        pool.execute(|| panic::panic_any(PanicOnDrop));
+
+        let started = Instant::now();
+        drop(pool);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn gives_lanes_back_workers_that_died() {
+        struct PanicOnDrop;
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                panic!("dropped");
+            }
+        }
+
+        let pool = ThreadPool::new(2);
+        pool.execute(|| panic::panic_any(PanicOnDrop));
+
+        // Wait for the worker's thread to end, by which time the lane should have had it back.
+        let started = Instant::now();
+        while pool.shared.queue.lock().unwrap().live_workers > 1 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let queue = pool
+            .shared
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        assert!(queue.lanes.iter().all(|lane| lane.running == 0));
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let policy: RetryPolicy<()> = RetryPolicy::new(10).backoff(Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        });
+
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(40), Duration::from_secs(1));
+    }
+}